@@ -15,5 +15,7 @@ fn main() {
                               println!("I am the follower!");
                               sleep(Duration::from_millis(250));
                           });
-    election.run();
+    if let Err(err) = election.run() {
+        println!("Election failed: {}", err);
+    }
 }
@@ -7,9 +7,13 @@ use std::time::Duration;
 
 fn main() {
     // This will retry ten times before failing.
-    lock!("example-lock", 
-          || {
-              println!("Just an example lock");
-              sleep(Duration::from_millis(250))
-          });
+    let result = lock!("example-lock",
+                       || {
+                           println!("Just an example lock");
+                           sleep(Duration::from_millis(250))
+                       });
+
+    if let Err(err) = result {
+        println!("Could not grab example-lock: {:?}", err);
+    }
 }
@@ -0,0 +1,25 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+//! A reference Metaparticle/Sync lock sidecar, keeping its locks in memory.
+//!
+//! It can be run as the `metaparticle-sync-sidecar` binary, or embedded to
+//! give integration tests a real sidecar to talk to.
+
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate json;
+extern crate tokio;
+
+mod server;
+mod store;
+
+pub use server::{Error, RunningSidecar, Sidecar, DEFAULT_ADDR, DEFAULT_TTL};
+pub use store::{Acquire, Lease, LockStore, Release};
@@ -0,0 +1,53 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+extern crate metaparticle_sync_sidecar as sidecar;
+
+use std::env;
+use std::process;
+use std::time::Duration;
+
+use sidecar::Sidecar;
+
+
+const USAGE: &'static str = "usage: metaparticle-sync-sidecar [--addr ADDR] [--ttl SECONDS]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn main() {
+    let mut sidecar = Sidecar::new();
+    let mut addr = sidecar::DEFAULT_ADDR.to_string();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--addr", Some(value)) => match value.parse() {
+                Ok(parsed) => {
+                    sidecar = sidecar.addr(parsed);
+                    addr = value;
+                },
+                Err(_) => usage(),
+            },
+            ("--ttl", Some(value)) => match value.parse() {
+                Ok(secs) => sidecar = sidecar.ttl(Duration::from_secs(secs)),
+                Err(_) => usage(),
+            },
+            _ => usage(),
+        }
+    }
+
+    println!("Serving locks on {}", addr);
+    if let Err(err) = sidecar.run() {
+        eprintln!("metaparticle-sync-sidecar: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use futures::sync::oneshot;
+use hyper::{self, Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::service_fn_ok;
+use json::JsonValue;
+use tokio::runtime::Runtime;
+
+use store::{Acquire, Lease, LockStore, Release};
+
+
+/// Where the sidecar listens unless told otherwise, to match the bindings'
+/// `DEFAULT_BASE_URI`.
+pub const DEFAULT_ADDR: &'static str = "127.0.0.1:8080";
+
+/// How long locks are held unless a request asks for another TTL.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+
+/// Errors starting or running the sidecar.
+#[derive(Debug)]
+pub enum Error {
+    /// The sidecar couldn't listen on its address.
+    Bind(hyper::Error),
+    /// The runtime serving requests couldn't be started.
+    Runtime(io::Error),
+    /// Serving requests failed.
+    Serve(hyper::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Bind(ref err) => write!(f, "could not listen: {}", err),
+            Error::Runtime(ref err) => write!(f, "could not start the runtime: {}", err),
+            Error::Serve(ref err) => write!(f, "could not serve requests: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Bind(_) => "could not listen",
+            Error::Runtime(_) => "could not start the runtime",
+            Error::Serve(_) => "could not serve requests",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Bind(ref err) => Some(err),
+            Error::Runtime(ref err) => Some(err),
+            Error::Serve(ref err) => Some(err),
+        }
+    }
+}
+
+
+/// The lock sidecar's HTTP server.
+///
+/// It serves the Metaparticle/Sync lock protocol under `/locks/<name>`:
+///
+///    * `GET` answers `200` with the lease as JSON if the lock is held, and
+///      `404` otherwise.
+///    * `PUT` acquires or renews the lock, answering `200` with the lease,
+///      or `409` with the current lease if someone else holds it.
+///    * `DELETE` releases the lock, answering `200` once nobody holds it, or
+///      `409` if someone else does.
+///
+/// Requests name their holder with a `holder` query parameter, and `PUT`s
+/// may ask for a TTL in milliseconds with `ttl_ms`. Leases are described as
+/// `{"holder": ..., "acquired_at": ..., "expires_at": ...}`, with the times
+/// in milliseconds since the Unix epoch.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync_sidecar as sidecar;
+///
+/// fn main() {
+///     let running = sidecar::Sidecar::new()
+///                       .addr("127.0.0.1:0".parse().unwrap())
+///                       .spawn()
+///                       .expect("a free port");
+///
+///     // .. point the bindings at running.base_uri()
+///     assert!(running.base_uri().starts_with("http://127.0.0.1:"));
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    addr: SocketAddr,
+    store: LockStore,
+}
+
+impl Sidecar {
+    pub fn new() -> Self {
+        Sidecar{
+            addr: DEFAULT_ADDR.parse().expect("the default address is valid"),
+            store: LockStore::new(DEFAULT_TTL),
+        }
+    }
+
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Holds locks for `ttl` unless a request asks for another TTL.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.store = LockStore::new(ttl);
+        self
+    }
+
+    /// Keeps locks in `store`, so that they can be inspected.
+    pub fn store(mut self, store: LockStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Serves requests until the process exits.
+    pub fn run(self) -> Result<(), Error> {
+        let store = self.store;
+        let server = Server::try_bind(&self.addr).map_err(Error::Bind)?
+                         .serve(move || {
+                             let store = store.clone();
+                             service_fn_ok(move |request| handle(&store, request))
+                         });
+
+        let mut runtime = Runtime::new().map_err(Error::Runtime)?;
+        runtime.block_on(server).map_err(Error::Serve)
+    }
+
+    /// Serves requests in the background until the returned `RunningSidecar`
+    /// is dropped.
+    pub fn spawn(self) -> Result<RunningSidecar, Error> {
+        let store = self.store.clone();
+        let server = Server::try_bind(&self.addr).map_err(Error::Bind)?
+                         .serve(move || {
+                             let store = store.clone();
+                             service_fn_ok(move |request| handle(&store, request))
+                         });
+        let addr = server.local_addr();
+
+        let (shutdown, stopped) = oneshot::channel();
+        let mut runtime = Runtime::new().map_err(Error::Runtime)?;
+        runtime.spawn(server.with_graceful_shutdown(stopped).map_err(|_| ()));
+
+        Ok(RunningSidecar{
+            addr: addr,
+            store: self.store,
+            shutdown: Some(shutdown),
+            runtime: Some(runtime),
+        })
+    }
+}
+
+impl Default for Sidecar {
+    fn default() -> Self {
+        Sidecar::new()
+    }
+}
+
+
+/// A sidecar serving requests in the background, until it's dropped.
+pub struct RunningSidecar {
+    addr: SocketAddr,
+    store: LockStore,
+    shutdown: Option<oneshot::Sender<()>>,
+    runtime: Option<Runtime>,
+}
+
+impl RunningSidecar {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The base URI to build locks with, e.g. `http://127.0.0.1:8080`.
+    pub fn base_uri(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn store(&self) -> &LockStore {
+        &self.store
+    }
+}
+
+impl Drop for RunningSidecar {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(runtime) = self.runtime.take() {
+            let _ = runtime.shutdown_now().wait();
+        }
+    }
+}
+
+
+fn handle(store: &LockStore, request: Request<Body>) -> Response<Body> {
+    let name = match request.uri().path().splitn(3, '/').collect::<Vec<_>>()[..] {
+        ["", "locks", name] if !name.is_empty() => name.to_string(),
+        _ => return respond(StatusCode::NOT_FOUND, None),
+    };
+    let (holder, ttl) = match parse_query(request.uri().query()) {
+        Some(query) => query,
+        None => return respond(StatusCode::BAD_REQUEST, None),
+    };
+    let holder = holder.as_ref().map(|holder| holder.as_str());
+
+    match *request.method() {
+        Method::GET => match store.get(&name) {
+            Some(lease) => respond(StatusCode::OK, Some(&lease)),
+            None => respond(StatusCode::NOT_FOUND, None),
+        },
+        Method::PUT => match store.put(&name, holder, ttl) {
+            Acquire::Acquired(lease) => respond(StatusCode::OK, Some(&lease)),
+            Acquire::Conflict(lease) => respond(StatusCode::CONFLICT, Some(&lease)),
+        },
+        Method::DELETE => match store.delete(&name, holder) {
+            Release::Released | Release::NotHeld => respond(StatusCode::OK, None),
+            Release::Conflict(lease) => respond(StatusCode::CONFLICT, Some(&lease)),
+        },
+        _ => respond(StatusCode::METHOD_NOT_ALLOWED, None),
+    }
+}
+
+// Reads the `holder` and `ttl_ms` parameters, or `None` when the TTL isn't
+// a number or the holder isn't properly escaped.
+fn parse_query(query: Option<&str>) -> Option<(Option<String>, Option<Duration>)> {
+    let (mut holder, mut ttl) = (None, None);
+    for param in query.unwrap_or("").split('&') {
+        let mut pair = param.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some("holder"), Some(value)) if !value.is_empty() => holder = Some(percent_decode(value)?),
+            (Some("ttl_ms"), Some(value)) => ttl = Some(Duration::from_millis(value.parse().ok()?)),
+            _ => {},
+        }
+    }
+    Some((holder, ttl))
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn respond(status: StatusCode, lease: Option<&Lease>) -> Response<Body> {
+    let body = match lease {
+        Some(lease) => Body::from(describe(lease).dump()),
+        None => Body::empty(),
+    };
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
+
+fn describe(lease: &Lease) -> JsonValue {
+    let mut body = object!{
+        "acquired_at" => as_millis(lease.acquired_at()),
+        "expires_at" => as_millis(lease.expires_at())
+    };
+    if let Some(holder) = lease.holder() {
+        body["holder"] = holder.into();
+    }
+    body
+}
+
+fn as_millis(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since.as_secs() * 1000 + since.subsec_millis() as u64
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+    use hyper::{Body, Client, Method, Request, StatusCode};
+    use json;
+    use tokio::runtime::Runtime;
+
+    use server::{RunningSidecar, Sidecar};
+
+    fn request(sidecar: &RunningSidecar, method: Method, path: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+                          .method(method)
+                          .uri(format!("{}{}", sidecar.base_uri(), path))
+                          .body(Body::empty())
+                          .unwrap();
+
+        let response = Client::new().request(request).and_then(|response| {
+            let status = response.status();
+            response.into_body().concat2().map(move |body| {
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            })
+        });
+        Runtime::new().unwrap().block_on(response).unwrap()
+    }
+
+    #[test]
+    fn test_serving_the_lock_protocol() {
+        let sidecar = Sidecar::new().addr("127.0.0.1:0".parse().unwrap()).spawn().unwrap();
+
+        assert_eq!(request(&sidecar, Method::GET, "/locks/lock").0, StatusCode::NOT_FOUND);
+
+        let (status, body) = request(&sidecar, Method::PUT, "/locks/lock?holder=replica-1&ttl_ms=5000");
+        assert_eq!(status, StatusCode::OK);
+        let lease = json::parse(&body).unwrap();
+        assert_eq!(lease["holder"], "replica-1");
+        assert_eq!(lease["expires_at"].as_u64().unwrap() - lease["acquired_at"].as_u64().unwrap(), 5000);
+
+        let (status, body) = request(&sidecar, Method::GET, "/locks/lock");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json::parse(&body).unwrap()["holder"], "replica-1");
+
+        assert_eq!(request(&sidecar, Method::PUT, "/locks/lock?holder=replica-2").0, StatusCode::CONFLICT);
+        assert_eq!(request(&sidecar, Method::DELETE, "/locks/lock?holder=replica-2").0, StatusCode::CONFLICT);
+        assert_eq!(request(&sidecar, Method::DELETE, "/locks/lock?holder=replica-1").0, StatusCode::OK);
+        assert_eq!(sidecar.store().get("lock"), None);
+        assert_eq!(request(&sidecar, Method::DELETE, "/locks/lock?holder=replica-1").0, StatusCode::OK);
+        assert_eq!(request(&sidecar, Method::PUT, "/locks/lock?holder=replica-2").0, StatusCode::OK);
+
+        let (status, body) = request(&sidecar, Method::PUT, "/locks/escaped?holder=replica%201%26x");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json::parse(&body).unwrap()["holder"], "replica 1&x");
+
+        assert_eq!(request(&sidecar, Method::PUT, "/locks/lock?ttl_ms=soon").0, StatusCode::BAD_REQUEST);
+        assert_eq!(request(&sidecar, Method::PUT, "/locks/lock?holder=replica%2").0, StatusCode::BAD_REQUEST);
+        assert_eq!(request(&sidecar, Method::POST, "/locks/lock").0, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(request(&sidecar, Method::GET, "/elections/lock").0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+
+/// A held lock, as the sidecar reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    holder: Option<String>,
+    acquired_at: SystemTime,
+    expires_at: SystemTime,
+    expires: Instant,
+}
+
+impl Lease {
+    /// Who holds the lock, unless it was acquired without naming a holder.
+    pub fn holder(&self) -> Option<&str> {
+        self.holder.as_ref().map(|holder| holder.as_str())
+    }
+
+    pub fn acquired_at(&self) -> SystemTime {
+        self.acquired_at
+    }
+
+    /// When the lock expires unless it's renewed.
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+}
+
+/// How a request to acquire or renew a lock went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Acquire {
+    Acquired(Lease),
+    /// Someone else holds the lock.
+    Conflict(Lease),
+}
+
+/// How a request to release a lock went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Release {
+    Released,
+    /// Nobody holds the lock.
+    NotHeld,
+    /// Someone else holds the lock.
+    Conflict(Lease),
+}
+
+
+/// In-memory lock storage with the sidecar's TTL semantics.
+///
+/// Locks expire their TTL after they were last acquired or renewed, and
+/// conflict while they're held by anyone else. Requests that don't name a
+/// holder all act as the same, anonymous, holder, as a sidecar next to a
+/// single application would see them.
+///
+/// Clones share the same locks.
+#[derive(Debug, Clone)]
+pub struct LockStore {
+    ttl: Duration,
+    locks: Arc<Mutex<HashMap<String, Lease>>>,
+}
+
+impl LockStore {
+    /// Creates a store holding locks for `ttl` unless a request asks for
+    /// another.
+    pub fn new(ttl: Duration) -> Self {
+        LockStore{
+            ttl: ttl,
+            locks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The lease on `name`, if it's held.
+    pub fn get(&self, name: &str) -> Option<Lease> {
+        let mut locks = self.locks.lock().unwrap();
+        LockStore::lease(&mut locks, name).cloned()
+    }
+
+    /// Acquires `name` for `holder`, or renews it when `holder` already
+    /// holds it, for `ttl` or the store's TTL.
+    pub fn put(&self, name: &str, holder: Option<&str>, ttl: Option<Duration>) -> Acquire {
+        let mut locks = self.locks.lock().unwrap();
+
+        let acquired_at = match LockStore::lease(&mut locks, name) {
+            Some(lease) if lease.holder() != holder => return Acquire::Conflict(lease.clone()),
+            Some(lease) => lease.acquired_at,
+            None => SystemTime::now(),
+        };
+
+        let ttl = ttl.unwrap_or(self.ttl);
+        let lease = Lease{
+            holder: holder.map(|holder| holder.to_string()),
+            acquired_at: acquired_at,
+            expires_at: SystemTime::now() + ttl,
+            expires: Instant::now() + ttl,
+        };
+        locks.insert(name.to_string(), lease.clone());
+        Acquire::Acquired(lease)
+    }
+
+    /// Releases `name` when `holder` holds it.
+    pub fn delete(&self, name: &str, holder: Option<&str>) -> Release {
+        let mut locks = self.locks.lock().unwrap();
+
+        match LockStore::lease(&mut locks, name) {
+            Some(lease) if lease.holder() != holder => return Release::Conflict(lease.clone()),
+            Some(_) => {},
+            None => return Release::NotHeld,
+        }
+        locks.remove(name);
+        Release::Released
+    }
+
+    // Forgets `name` once it's expired, and returns its lease otherwise.
+    fn lease<'a>(locks: &'a mut HashMap<String, Lease>, name: &str) -> Option<&'a Lease> {
+        let expired = match locks.get(name) {
+            Some(lease) => lease.expires <= Instant::now(),
+            None => return None,
+        };
+        if expired {
+            locks.remove(name);
+        }
+        locks.get(name)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use store::{Acquire, LockStore, Release};
+
+    #[test]
+    fn test_holding_locks_until_they_expire() {
+        let store = LockStore::new(Duration::from_millis(200));
+
+        match store.put("lock", Some("replica-1"), None) {
+            Acquire::Acquired(ref lease) => assert_eq!(lease.holder(), Some("replica-1")),
+            other => panic!("expected to acquire the lock, got {:?}", other),
+        }
+        match store.put("lock", Some("replica-2"), None) {
+            Acquire::Conflict(ref lease) => assert_eq!(lease.holder(), Some("replica-1")),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+
+        // Renewals keep the lock, and its acquisition time.
+        let acquired_at = store.get("lock").unwrap().acquired_at();
+        sleep(Duration::from_millis(100));
+        match store.put("lock", Some("replica-1"), Some(Duration::from_millis(300))) {
+            Acquire::Acquired(ref lease) => assert_eq!(lease.acquired_at(), acquired_at),
+            other => panic!("expected to renew the lock, got {:?}", other),
+        }
+
+        sleep(Duration::from_millis(200));
+        assert!(store.get("lock").is_some());
+        sleep(Duration::from_millis(150));
+        assert_eq!(store.get("lock"), None);
+        match store.put("lock", Some("replica-2"), None) {
+            Acquire::Acquired(_) => {},
+            other => panic!("expected to acquire the lock, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_releasing_locks() {
+        let store = LockStore::new(Duration::from_secs(30));
+
+        store.put("lock", None, None);
+        match store.delete("lock", Some("replica-1")) {
+            Release::Conflict(ref lease) => assert_eq!(lease.holder(), None),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(store.delete("lock", None), Release::Released);
+        assert_eq!(store.delete("lock", None), Release::NotHeld);
+        assert_eq!(store.get("lock"), None);
+    }
+}
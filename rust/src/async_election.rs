@@ -0,0 +1,317 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, IntoFuture};
+use futures::future::Loop;
+use tokio::timer::Delay;
+
+use async_lock::{self, AsyncLockClient, LockFuture};
+use election::Handler;
+use error::SyncError;
+
+
+// How often a paused election checks whether it's been shut down.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+type HandlerFn = Box<Fn() -> LockFuture<()> + Send + Sync>;
+
+fn boxed<F, R>(handler: F) -> HandlerFn
+where F: Fn() -> R + Send + Sync + 'static,
+      R: IntoFuture<Item = (), Error = SyncError>,
+      R::Future: Send + 'static,
+{
+    Box::new(move || Box::new(handler().into_future()) as LockFuture<()>)
+}
+
+fn hook(handler: &Option<Arc<HandlerFn>>) -> LockFuture<()> {
+    match *handler {
+        Some(ref handler) => handler(),
+        None => Box::new(future::ok(())),
+    }
+}
+
+
+/// Helper macro for invoking async election synchronization
+///
+/// Like `elect!`, but builds an `AsyncElection` whose handlers return
+/// futures.
+///
+/// # Example
+///
+/// ```no_run
+/// #[macro_use]
+/// extern crate metaparticle_sync as sync;
+/// extern crate futures;
+/// extern crate tokio;
+///
+/// use futures::Future;
+///
+/// fn main() {
+///     let election = async_elect!("database-migration",
+///                                 || Ok(()),   // This future runs when leader
+///                                 || Ok(()));  // This future runs when follower
+///
+///     tokio::run(election.run().map_err(|err| println!("Election failed: {}", err)));
+/// }
+/// ```
+///
+#[macro_export]
+macro_rules! async_elect {
+    ($name: tt) => ( $crate::AsyncElection::new($name,
+                                                $crate::DEFAULT_BASE_URI,
+                                                || -> Result<(), $crate::SyncError> { Ok(()) },
+                                                || -> Result<(), $crate::SyncError> { Ok(()) })
+    );
+    ($name: tt, $leaderfn:expr, $followerfn:expr) => {{
+        let mut election = async_elect!($name);
+        election.add_handler($crate::Handler::Leader  , $leaderfn);
+        election.add_handler($crate::Handler::Follower, $followerfn);
+        election
+    }};
+}
+
+
+/// Metaparticle.io Election primitive for futures.
+///
+/// `AsyncElection` campaigns through an `AsyncLock`, so `run` returns a
+/// future that must be run on a tokio runtime.
+///
+/// # Example
+///
+/// ```no_run
+/// extern crate metaparticle_sync as sync;
+/// extern crate futures;
+/// extern crate tokio;
+///
+/// use futures::Future;
+///
+/// fn main() {
+///     let elector = sync::AsyncElection::new("database-migration",
+///                                            sync::DEFAULT_BASE_URI,
+///                                            || Ok(()),
+///                                            || Ok(()));
+///
+///     tokio::run(elector.run().map_err(|err| println!("Election failed: {}", err)));
+/// }
+/// ```
+#[derive(Clone)]
+pub struct AsyncElection {
+    lock: async_lock::AsyncLock,
+    running: Arc<AtomicBool>,
+
+    leader_fn: Arc<HandlerFn>,
+    follower_fn: Arc<HandlerFn>,
+    elected_fn: Option<Arc<HandlerFn>>,
+    demoted_fn: Option<Arc<HandlerFn>>,
+}
+
+impl AsyncElection {
+    pub fn new<T, L, LR, F, FR>(name: T, base_uri: T, leader_fn: L, follower_fn: F) -> Self
+    where T: Into<String>,
+          L: Fn() -> LR + Send + Sync + 'static,
+          LR: IntoFuture<Item = (), Error = SyncError>,
+          LR::Future: Send + 'static,
+          F: Fn() -> FR + Send + Sync + 'static,
+          FR: IntoFuture<Item = (), Error = SyncError>,
+          FR::Future: Send + 'static,
+    {
+        AsyncElection{
+            lock: async_lock::AsyncLock::new(name, base_uri, 10),
+            running: Arc::new(AtomicBool::new(false)),
+            leader_fn: Arc::new(boxed(leader_fn)),
+            follower_fn: Arc::new(boxed(follower_fn)),
+            elected_fn: None,
+            demoted_fn: None,
+        }
+    }
+
+    /// Campaigns every `interval` rather than every 10 seconds, and
+    /// heartbeats the lock as often while leading.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.lock = self.lock.heartbeat_interval(interval);
+        self
+    }
+
+    /// Campaigns through `client` rather than over HTTP.
+    pub fn client<C: AsyncLockClient + 'static>(mut self, client: C) -> Self {
+        self.lock = self.lock.client(client);
+        self
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Campaigns for leadership until `shutdown`, resolving once the
+    /// election has stopped. When elected, the `Elected`, leader and
+    /// `Demoted` futures run in turn; when the lock is held elsewhere, or
+    /// leadership ends, the follower future runs, once for every stretch of
+    /// following. Either way the election campaigns again every heartbeat
+    /// interval. Failing to reach the sidecar is retried in the same way,
+    /// while other errors stop the election and fail the future.
+    pub fn run(&self) -> LockFuture<()> {
+        let election = self.clone();
+        self.running.store(true, Ordering::Relaxed);
+
+        Box::new(future::loop_fn(false, move |following| {
+            let election = election.clone();
+            election.campaign(following).and_then(move |following| -> LockFuture<Loop<(), bool>> {
+                if !election.is_running() {
+                    return Box::new(future::ok(Loop::Break(())))
+                }
+                Box::new(election.pause().map(move |()| Loop::Continue(following)))
+            })
+        }))
+    }
+
+    // Campaigns once, running the leader or follower futures as it turns
+    // out, and resolves to whether this replica is following.
+    fn campaign(&self, following: bool) -> LockFuture<bool> {
+        let leader_fn = self.leader_fn.clone();
+        let follower_fn = self.follower_fn.clone();
+        let elected_fn = self.elected_fn.clone();
+        let demoted_fn = self.demoted_fn.clone();
+        let running = self.running.clone();
+        let name = self.lock.name().to_string();
+
+        // Leading runs `Elected`, the leader and then `Demoted` handlers in
+        // turn, the latter even if the leader fails.
+        let leading = future::lazy(move || hook(&elected_fn))
+                          .and_then(move |()| leader_fn())
+                          .then(move |result| hook(&demoted_fn).then(move |_| result));
+
+        Box::new(self.lock.lock(leading).then(move |result| -> LockFuture<bool> {
+            let following = match result {
+                Ok(()) => false,
+                Err(SyncError::Held) => following,
+                Err(SyncError::LockLost) => {
+                    warn!("Lost leadership of {}", election: name);
+                    false
+                },
+                Err(ref err) if err.is_retryable() => {
+                    warn!("Could not campaign for {}: {}",
+                          election: name,
+                          error: err.to_string());
+                    return Box::new(future::ok(following))
+                },
+                Err(err) => {
+                    running.store(false, Ordering::Relaxed);
+                    return Box::new(future::err(err))
+                },
+            };
+
+            if following || !running.load(Ordering::Relaxed) {
+                return Box::new(future::ok(following))
+            }
+            Box::new(follower_fn().map(|()| true))
+        }))
+    }
+
+    // Waits for a heartbeat interval, or until the election is shut down.
+    fn pause(&self) -> LockFuture<()> {
+        let running = self.running.clone();
+        let deadline = Instant::now() + self.lock.interval();
+
+        Box::new(future::loop_fn((), move |()| -> LockFuture<Loop<(), ()>> {
+            let now = Instant::now();
+            if !running.load(Ordering::Relaxed) || now >= deadline {
+                return Box::new(future::ok(Loop::Break(())))
+            }
+
+            // A timer error means the runtime is going away, in which case
+            // checking again straight away is harmless.
+            let wake = cmp::min(deadline, now + SHUTDOWN_POLL);
+            Box::new(Delay::new(wake).then(|_| Ok(Loop::Continue(()))))
+        }))
+    }
+
+    /// Stops the election once the campaign, leader or follower future in
+    /// progress has resolved, without waiting for the next heartbeat.
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn add_handler<F, R>(&mut self, typ: Handler, handler: F)
+    where F: Fn() -> R + Send + Sync + 'static,
+          R: IntoFuture<Item = (), Error = SyncError>,
+          R::Future: Send + 'static,
+    {
+        match typ {
+            Handler::Leader   => self.leader_fn = Arc::new(boxed(handler)),
+            Handler::Follower => self.follower_fn = Arc::new(boxed(handler)),
+            Handler::Elected  => self.elected_fn = Some(Arc::new(boxed(handler))),
+            Handler::Demoted  => self.demoted_fn = Some(Arc::new(boxed(handler))),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use futures::{future, Future};
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    use error::SyncError;
+    use testing::InMemoryLockServer;
+
+    #[test]
+    fn test_run_async_election() {
+        let server = InMemoryLockServer::new();
+        let leading: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let following: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let elections: Vec<_> = (0..3).map(|i| {
+            let name = format!("client{}", i);
+            let (leader_name, follower_name) = (name.clone(), name.clone());
+            let (leading, following) = (leading.clone(), following.clone());
+
+            let elector = async_elect!("fake-lock",
+                                       move || {
+                                           leading.lock().unwrap().push(leader_name.clone());
+                                           Delay::new(Instant::now() + Duration::from_millis(250))
+                                               .then(|_| Ok::<_, SyncError>(()))
+                                       },
+                                       move || {
+                                           following.lock().unwrap().push(follower_name.clone());
+                                           Ok(())
+                                       });
+            elector.heartbeat_interval(Duration::from_millis(100))
+                   .client(server.client(name))
+        }).collect();
+
+        // Every replica keeps campaigning after leading or following, until
+        // it's shut down.
+        let stopping = elections.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1000));
+            for elector in &stopping {
+                elector.shutdown();
+            }
+        });
+
+        let runs: Vec<_> = elections.iter().map(|elector| elector.run()).collect();
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(future::join_all(runs)).unwrap();
+
+        let leading = leading.lock().unwrap();
+        assert_eq!(leading[0], "client0");
+        assert!(leading.len() >= 3, "only {:?} led", *leading);
+        assert!(following.lock().unwrap().len() >= 3);
+        assert_eq!(server.holder("http://localhost:8080/locks/fake-lock"), None);
+    }
+}
@@ -0,0 +1,607 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::fmt::Debug;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, IntoFuture, Stream};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use hyper::{self, Body, Method, Request, Response, Uri};
+use hyper::client::HttpConnector;
+use requests::StatusCode;
+use tokio;
+use tokio::executor::{DefaultExecutor, Executor};
+use tokio::timer::{Delay, Interval};
+
+use client::{AcquireStatus, LockHolder, LockStatus, ReleaseStatus};
+use error::SyncError;
+use lock::DEFAULT_TTL;
+use retry::as_millis;
+
+
+/// Future returned by the async lock primitives.
+pub type LockFuture<T> = Box<Future<Item = T, Error = SyncError> + Send>;
+
+
+/// Helper macro for invoking async lock synchronization
+///
+/// Like `lock!`, but builds an `AsyncLock`, and the 2-ary form returns a
+/// future that runs the given future while holding the lock.
+///
+/// # Example
+///
+/// ```
+/// #[ macro_use ]
+/// extern crate metaparticle_sync as sync;
+/// extern crate futures;
+/// extern crate tokio;
+///
+/// use futures::{future, Future};
+///
+/// fn main() {
+///     let work = async_lock!("some-lock", future::lazy(|| {
+///         // .. do some work
+///         Ok::<(), sync::SyncError>(())
+///     }));
+///
+///     tokio::run(work.map_err(|err| println!("Could not lock: {}", err)));
+/// }
+/// ```
+///
+#[macro_export]
+macro_rules! async_lock {
+    ($name:tt) => ( $crate::AsyncLock::new($name, $crate::DEFAULT_BASE_URI, 10) );
+    ($name:tt, $future:expr) => {{
+        let lock = async_lock!($name);
+        lock.lock( $future )
+    }}
+}
+
+
+/// The transport `AsyncLock` and `AsyncElection` use to reach the sidecar,
+/// like `LockClient` for futures.
+pub trait AsyncLockClient: Debug+Send+Sync {
+    fn get_lock(&self, lock: &str) -> LockFuture<LockStatus>;
+
+    /// Acquires the lock, or renews it when it's already held by the caller.
+    fn put_lock(&self, lock: &str) -> LockFuture<AcquireStatus>;
+
+    /// Releases a held lock. Clients for sidecars that can't release locks
+    /// answer `Unsupported`, leaving the lock to expire with its TTL.
+    fn delete_lock(&self, _lock: &str) -> LockFuture<ReleaseStatus> {
+        Box::new(future::ok(ReleaseStatus::Unsupported))
+    }
+}
+
+
+/// The HTTP client for the sidecar, which asks for a TTL when renewing.
+#[derive(Debug)]
+struct HttpClient {
+    http: hyper::Client<HttpConnector>,
+    ttl: Option<Duration>,
+}
+impl HttpClient {
+    fn new(ttl: Option<Duration>) -> Self {
+        HttpClient{
+            http: hyper::Client::new(),
+            ttl: ttl,
+        }
+    }
+
+    fn put_uri(&self, lock: &str) -> String {
+        match self.ttl {
+            Some(ttl) => format!("{}?ttl_ms={}", lock, as_millis(ttl)),
+            None => lock.to_string(),
+        }
+    }
+
+    fn send(&self, method: Method, lock: &str) -> LockFuture<Response<Body>> {
+        let uri = match lock.parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_)  => return Box::new(future::err(SyncError::InvalidUri(lock.to_string()))),
+        };
+
+        let mut request = Request::new(Body::empty());
+        *request.method_mut() = method;
+        *request.uri_mut() = uri;
+
+        Box::new(self.http.request(request).from_err())
+    }
+}
+
+fn status_of(response: &Response<Body>) -> StatusCode {
+    StatusCode::from_u16(response.status().as_u16())
+}
+
+impl AsyncLockClient for HttpClient {
+    fn get_lock(&self, lock: &str) -> LockFuture<LockStatus> {
+        Box::new(self.send(Method::GET, lock).and_then(|response| {
+            let status = status_of(&response);
+            response.into_body().concat2().from_err().and_then(move |body| {
+                let holder = str::from_utf8(&body).ok().and_then(LockHolder::parse);
+                LockStatus::from_status(status, holder)
+            })
+        }))
+    }
+
+    fn put_lock(&self, lock: &str) -> LockFuture<AcquireStatus> {
+        Box::new(self.send(Method::PUT, &self.put_uri(lock))
+                     .and_then(|response| AcquireStatus::from_status(status_of(&response))))
+    }
+
+    fn delete_lock(&self, lock: &str) -> LockFuture<ReleaseStatus> {
+        Box::new(self.send(Method::DELETE, lock)
+                     .and_then(|response| ReleaseStatus::from_status(status_of(&response))))
+    }
+}
+
+
+/// Keeps an `AsyncLock` held while the heartbeat task runs, and releases it
+/// on the sidecar when dropped.
+struct Hold {
+    uri: String,
+    client: Arc<AsyncLockClient>,
+    running: Arc<AtomicBool>,
+    locked: Arc<AtomicBool>,
+    // Resolves once the heartbeat's latest renewal has reached the sidecar,
+    // so that releasing the lock waits for one in flight rather than racing
+    // it.
+    renewing: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+}
+
+impl Hold {
+    fn is_held(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if !self.locked.swap(false, Ordering::Relaxed) {
+            return
+        }
+
+        // A renewal that was dropped rather than sent can't retake the lock
+        // either, so it's released either way.
+        let release = match self.renewing.lock().unwrap().take() {
+            Some(renewal) => {
+                let (uri, client) = (self.uri.clone(), self.client.clone());
+                Box::new(renewal.then(move |_| client.delete_lock(&uri)))
+            },
+            None => self.client.delete_lock(&self.uri),
+        };
+
+        let uri = self.uri.clone();
+        let release = release.then(move |result| {
+            match result {
+                Ok(ReleaseStatus::Released) => {},
+                Ok(ReleaseStatus::Unsupported) => {
+                    info!("Lock {} will expire with its TTL", lock: uri)
+                },
+                Ok(ReleaseStatus::NotHeld) => {
+                    error!("Could not release lock {}: it is held elsewhere", lock: uri)
+                },
+                Err(err) => {
+                    error!("Could not release lock {}: {}",
+                           lock: uri,
+                           error: err.to_string())
+                },
+            }
+            Ok(())
+        });
+
+        // Without a runtime to release on, the lock expires with its TTL.
+        let _ = DefaultExecutor::current().spawn(Box::new(release));
+    }
+}
+
+
+/// Metaparticle.io Lock primitive for futures.
+///
+/// `AsyncLock` speaks the same sidecar protocol as `Lock`, but heartbeats
+/// from a task on the tokio runtime instead of a dedicated thread, so every
+/// future it returns must be run on a tokio runtime.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+/// extern crate futures;
+/// extern crate tokio;
+///
+/// use futures::{future, Future};
+///
+/// fn main() {
+///     let lock = sync::AsyncLock::new("some-held-lock", sync::DEFAULT_BASE_URI, 10);
+///
+///     // Attempt to lock without retrying.
+///     let work = lock.lock(future::lazy(|| {
+///         // do some important work
+///         Ok::<(), sync::SyncError>(())
+///     }));
+///
+///     tokio::run(work.map_err(|err| println!("Could not lock: {}", err)));
+/// }
+/// ```
+#[derive(Clone)]
+pub struct AsyncLock {
+    name: String,
+    base_uri: String,
+    interval: Duration,
+    ttl: Option<Duration>,
+
+    locked: Arc<AtomicBool>,
+    client: Arc<AsyncLockClient>,
+    // Whether `client` is the HTTP one, which is rebuilt to ask for `ttl`.
+    http: bool,
+}
+impl AsyncLock {
+    pub fn new<S: Into<String>>(name: S, base_uri: S, interval: u64) -> Self {
+        AsyncLock{
+            name: name.into(),
+            base_uri: base_uri.into(),
+            interval: Duration::from_secs(interval),
+            ttl: None,
+
+            client: Arc::new(HttpClient::new(None)),
+            http: true,
+            locked: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Heartbeats the lock every `interval` rather than the one it was
+    /// created with.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Talks to the sidecar through `client` rather than over HTTP.
+    pub fn client<C: AsyncLockClient + 'static>(mut self, client: C) -> Self {
+        self.client = Arc::new(client);
+        self.http = false;
+        self
+    }
+
+    /// How long the sidecar holds the lock after each heartbeat, which it's
+    /// asked for by every PUT over HTTP; a client given to `client` keeps to
+    /// its own. The lock is given up as lost once heartbeats have failed to
+    /// renew it for close to this long, or to `DEFAULT_TTL` without one.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        if self.http {
+            self.client = Arc::new(HttpClient::new(self.ttl));
+        }
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn uri(&self) -> String {
+        format!("{}/locks/{}", self.base_uri, self.name)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Runs `func` while holding the lock, without retrying if it's held
+    /// elsewhere. Resolves to the future's result.
+    pub fn lock<F>(&self, func: F) -> Box<Future<Item = F::Item, Error = F::Error> + Send>
+    where F: IntoFuture + Send + 'static,
+          F::Future: Send + 'static,
+          F::Item: Send + 'static,
+          F::Error: From<SyncError> + Send + 'static,
+    {
+        self._lock(0, func)
+    }
+
+    /// Like `lock`, but waits for the lock and retries up to 10 times.
+    pub fn lock_with_retry<F>(&self, func: F) -> Box<Future<Item = F::Item, Error = F::Error> + Send>
+    where F: IntoFuture + Send + 'static,
+          F::Future: Send + 'static,
+          F::Item: Send + 'static,
+          F::Error: From<SyncError> + Send + 'static,
+    {
+        self._lock(10, func)
+    }
+
+    /// Like `lock`, but waits for the lock until it's acquired.
+    pub fn lock_with_retry_forever<F>(&self, func: F) -> Box<Future<Item = F::Item, Error = F::Error> + Send>
+    where F: IntoFuture + Send + 'static,
+          F::Future: Send + 'static,
+          F::Item: Send + 'static,
+          F::Error: From<SyncError> + Send + 'static,
+    {
+        self._lock(-1, func)
+    }
+
+    fn _lock<F>(&self, retry: i8, func: F) -> Box<Future<Item = F::Item, Error = F::Error> + Send>
+    where F: IntoFuture + Send + 'static,
+          F::Future: Send + 'static,
+          F::Item: Send + 'static,
+          F::Error: From<SyncError> + Send + 'static,
+    {
+        let name = self.name.clone();
+
+        Box::new(self.acquire(retry).from_err().and_then(move |hold| {
+            func.into_future().then(move |result| {
+                let lost = !hold.is_held();
+                drop(hold);
+
+                if lost {
+                    error!("Lost lock {} while it was held", lock: name);
+                    return Err(F::Error::from(SyncError::LockLost))
+                }
+                result
+            })
+        }))
+    }
+
+    fn attempt(&self) -> LockFuture<AcquireStatus> {
+        let uri = self.uri();
+        let client = self.client.clone();
+
+        Box::new(self.client.get_lock(&uri).and_then(move |_| client.put_lock(&uri)))
+    }
+
+    fn acquire(&self, retry: i8) -> LockFuture<Hold> {
+        let lock = self.clone();
+
+        Box::new(future::loop_fn(retry, move |retry| {
+            let lock = lock.clone();
+
+            lock.attempt().and_then(move |status| -> LockFuture<Loop<Hold, i8>> {
+                match status {
+                    AcquireStatus::Acquired => Box::new(future::ok(Loop::Break(lock.hold_heartbeat()))),
+                    AcquireStatus::Conflict => {
+                        if retry == 0 {
+                            info!("Couldn't grab lock {} retry {}", lock: lock.name, retry: retry);
+                            return Box::new(future::err(SyncError::Held))
+                        }
+
+                        let mut retry = retry;
+                        if retry != -1 {
+                            retry -= 1;
+                        }
+
+                        // A timer error means the runtime is going away, in
+                        // which case retrying straight away is harmless.
+                        Box::new(Delay::new(Instant::now() + lock.interval)
+                                     .then(move |_| Ok(Loop::Continue(retry))))
+                    },
+                }
+            })
+        }).or_else(move |err| match err {
+            SyncError::Held if retry != 0 => Err(SyncError::RetriesExhausted),
+            err => Err(err),
+        }))
+    }
+
+    fn hold_heartbeat(&self) -> Hold {
+        let uri = self.uri();
+        let client = self.client.clone();
+        let locked = self.locked.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let renewing = Arc::new(Mutex::new(None));
+
+        locked.store(true, Ordering::Relaxed);
+
+        let hold = Hold{
+            uri: uri.clone(),
+            client: client.clone(),
+            running: running.clone(),
+            locked: locked.clone(),
+            renewing: renewing.clone(),
+        };
+
+        let beating = running.clone();
+        let beats = Interval::new(Instant::now() + self.interval, self.interval)
+                        .map_err(|_| ())
+                        .take_while(move |_| Ok(beating.load(Ordering::Relaxed)));
+
+        let ttl = self.ttl.unwrap_or(DEFAULT_TTL);
+        let interval = self.interval;
+        let renewed = Arc::new(Mutex::new(Instant::now()));
+        tokio::spawn(beats.for_each(move |_| {
+            let uri = uri.clone();
+            let lock_uri = uri.clone();
+            let client = client.clone();
+            let locked = locked.clone();
+            let renewed = renewed.clone();
+            let running = running.clone();
+            let renewing = renewing.clone();
+            let sent = Instant::now();
+
+            // Someone else renewing the lock means it's theirs now.
+            let renewal = client.get_lock(&uri).and_then(move |status| -> LockFuture<bool> {
+                match status {
+                    LockStatus::Held(_) => {
+                        // Renewing once the hold is dropped would take the
+                        // lock back after its release.
+                        let mut in_flight = renewing.lock().unwrap();
+                        if !running.load(Ordering::Relaxed) {
+                            return Box::new(future::ok(true))
+                        }
+
+                        let (done, renewal) = oneshot::channel();
+                        *in_flight = Some(renewal);
+                        let renewing = renewing.clone();
+                        Box::new(client.put_lock(&lock_uri).then(move |result| {
+                            renewing.lock().unwrap().take();
+                            let _ = done.send(());
+                            result.map(|status| status == AcquireStatus::Acquired)
+                        }))
+                    },
+                    LockStatus::Free => Box::new(future::ok(false)),
+                }
+            });
+
+            renewal.then(move |result| {
+                match result {
+                    Ok(true) => {
+                        *renewed.lock().unwrap() = sent;
+                        return Ok(())
+                    },
+                    Ok(false) => {
+                        error!("Lost lock {}", lock: uri);
+                        locked.store(false, Ordering::Relaxed);
+                        return Err(())
+                    },
+                    Err(err) => {
+                        warn!("Could not renew lock {}: {}",
+                              lock: uri,
+                              error: err.to_string())
+                    },
+                }
+
+                // Give the lock up before the sidecar expires it, when the
+                // next heartbeat could be too late to renew it.
+                if Instant::now() + interval >= *renewed.lock().unwrap() + ttl {
+                    error!("Could not renew lock {} within its TTL", lock: uri);
+                    locked.store(false, Ordering::Relaxed);
+                    return Err(())
+                }
+                Ok(())
+            })
+        }));
+
+        hold
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use futures::{future, Future};
+    use futures::sync::oneshot;
+    use tokio::runtime::{Builder, Runtime};
+    use tokio::timer::Delay;
+
+    use async_lock::{AsyncLock, HttpClient};
+    use client::LockClient;
+    use error::SyncError;
+    use testing::{Fault, FaultyClient, InMemoryLockClient, InMemoryLockServer};
+
+    fn new_lock(client: &InMemoryLockClient, lock: &str) -> AsyncLock {
+        AsyncLock::new(lock, "http://localhost:8080", 1).client(client.clone())
+    }
+
+    #[test]
+    fn test_async_locking_without_retrying() {
+        let server = InMemoryLockServer::new();
+        let lock  = new_lock(&server.client("client1"), "good");
+        let lock2 = new_lock(&server.client("client2"), "good");
+
+        let mut runtime = Runtime::new().unwrap();
+
+        let result2 = lock2.lock(future::lazy(|| Ok::<_, SyncError>("work")));
+        let result = runtime.block_on(lock.lock(future::lazy(move || result2.then(Ok::<_, SyncError>))));
+
+        match result.unwrap() {
+            Err(SyncError::Held) => {},
+            other => panic!("expected SyncError::Held, got {:?}", other),
+        }
+        assert_eq!(lock.is_locked(), false);
+        assert_eq!(server.holder("http://localhost:8080/locks/good"), None);
+
+        let result = runtime.block_on(lock2.lock(future::lazy(|| Ok::<_, SyncError>("work"))));
+        assert_eq!(result.unwrap(), "work");
+    }
+
+    #[test]
+    fn test_async_locking_with_retrying() {
+        let server = InMemoryLockServer::new();
+        let lock  = new_lock(&server.client("client1"), "good");
+        let lock2 = new_lock(&server.client("client2"), "good");
+
+        let mut runtime = Runtime::new().unwrap();
+
+        // The second lock waits for the first to be released.
+        let held = Delay::new(Instant::now() + Duration::from_millis(500)).then(|_| Ok::<_, SyncError>(()));
+        let result = lock.lock(held);
+        let result2 = lock2.lock_with_retry(future::lazy(|| Ok::<_, SyncError>(())));
+
+        let (result, result2) = runtime.block_on(result.then(Ok::<_, SyncError>)
+                                                     .join(result2.then(Ok::<_, SyncError>)))
+                                       .unwrap();
+        assert!(result.is_ok());
+        assert!(result2.is_ok());
+    }
+
+    #[test]
+    fn test_async_losing_lock_to_conflicting_renewal() {
+        let server = InMemoryLockServer::new();
+        let lock = new_lock(&server.client("client1"), "stolen");
+
+        // Half a heartbeat in, the lock expires and client2 takes it over, so
+        // the next renewal conflicts.
+        let thief = server.clone();
+        let stolen = Delay::new(Instant::now() + Duration::from_millis(500)).then(move |_| {
+            thief.expire("http://localhost:8080/locks/stolen");
+            thief.client("client2").put_lock("http://localhost:8080/locks/stolen").unwrap();
+            Delay::new(Instant::now() + Duration::from_millis(1000))
+        }).then(|_| Ok::<_, SyncError>(()));
+
+        let mut runtime = Runtime::new().unwrap();
+        match runtime.block_on(lock.lock(stolen)) {
+            Err(SyncError::LockLost) => {},
+            other => panic!("expected SyncError::LockLost, got {:?}", other),
+        }
+        assert_eq!(lock.is_locked(), false);
+        assert_eq!(server.holder("http://localhost:8080/locks/stolen").unwrap().id(), "client2");
+    }
+
+    #[test]
+    fn test_async_releasing_during_renewal() {
+        let server = InMemoryLockServer::new();
+        let client = FaultyClient::new(server.client("client1"));
+        let lock = AsyncLock::new("renewing", "http://localhost:8080", 1).client(client.clone());
+
+        // The first heartbeat's renewal is still on its way to the sidecar
+        // when the work is done, and lands before the lock is released
+        // rather than taking it back afterwards.
+        client.script(vec![Fault::Pass, Fault::Pass, Fault::Pass, Fault::Delay(Duration::from_millis(300))]);
+        let (done, work) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1100));
+            done.send(()).unwrap();
+        });
+        let work = work.map_err(|_| SyncError::LockLost);
+
+        // The renewal blocks its worker, so the work finishes on another.
+        let mut runtime = Builder::new().core_threads(2).build().unwrap();
+        runtime.block_on(lock.lock(work)).unwrap();
+        runtime.block_on(Delay::new(Instant::now() + Duration::from_millis(500))).unwrap();
+        assert_eq!(server.holder("http://localhost:8080/locks/renewing"), None);
+    }
+
+    #[test]
+    fn test_asking_for_ttl() {
+        let client = HttpClient::new(Some(Duration::from_millis(1500)));
+        assert_eq!(client.put_uri("http://localhost:8080/locks/lock"),
+                   "http://localhost:8080/locks/lock?ttl_ms=1500");
+
+        let client = HttpClient::new(None);
+        assert_eq!(client.put_uri("http://localhost:8080/locks/lock"), "http://localhost:8080/locks/lock");
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+
+/// Cooperative cancellation for work done while holding a lock.
+///
+/// Clones share the same state, so a token handed to a closure trips when
+/// the lock it was issued for is lost or unlocked, or when the election it
+/// belongs to shuts down. Long-running work should poll `is_cancelled()`.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// fn main() {
+///     let lock = sync::Lock::new("some-held-lock", sync::DEFAULT_BASE_URI, 10);
+///
+///     let _ = lock.lock_cancellable(|token| {
+///         for _batch in 0..100 {
+///             if token.is_cancelled() {
+///                 break
+///             }
+///             // .. migrate a batch
+///         }
+///     });
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
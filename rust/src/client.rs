@@ -0,0 +1,390 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use json;
+use requests::{delete, get, put, StatusCode};
+
+use error::SyncError;
+use retry::as_millis;
+
+
+/// What the sidecar knows about a lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockStatus {
+    /// Someone holds the lock, named if the sidecar reports holders.
+    Held(Option<LockHolder>),
+    /// Nobody holds the lock.
+    Free,
+}
+
+/// How the sidecar answered a request to acquire or renew a lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireStatus {
+    /// The lock was acquired, or renewed by its holder.
+    Acquired,
+    /// Someone else holds the lock.
+    Conflict,
+}
+
+/// How the sidecar answered a request to release a lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseStatus {
+    /// The lock was released.
+    Released,
+    /// Someone else holds the lock.
+    NotHeld,
+    /// The sidecar can't release locks, so the lock expires with its TTL.
+    Unsupported,
+}
+
+impl LockStatus {
+    // Reads the sidecar's answer to a GET: 200 while the lock is held, and
+    // 404 once it's free.
+    pub(crate) fn from_status(status: StatusCode, holder: Option<LockHolder>) -> Result<Self, SyncError> {
+        match status {
+            StatusCode::Ok => Ok(LockStatus::Held(holder)),
+            StatusCode::NotFound => Ok(LockStatus::Free),
+            status => Err(SyncError::UnexpectedStatus(status)),
+        }
+    }
+}
+
+impl AcquireStatus {
+    // Reads the sidecar's answer to a PUT: 200 once the lock is acquired or
+    // renewed, and 409 while someone else holds it.
+    pub(crate) fn from_status(status: StatusCode) -> Result<Self, SyncError> {
+        match status {
+            StatusCode::Ok => Ok(AcquireStatus::Acquired),
+            StatusCode::Conflict => Ok(AcquireStatus::Conflict),
+            status => Err(SyncError::UnexpectedStatus(status)),
+        }
+    }
+}
+
+impl ReleaseStatus {
+    // Reads the sidecar's answer to a DELETE, where 404 and 405 mean the
+    // sidecar has no DELETE route.
+    pub(crate) fn from_status(status: StatusCode) -> Result<Self, SyncError> {
+        match status {
+            StatusCode::Ok | StatusCode::NoContent => Ok(ReleaseStatus::Released),
+            StatusCode::NotFound | StatusCode::MethodNotAllowed => Ok(ReleaseStatus::Unsupported),
+            StatusCode::Conflict => Ok(ReleaseStatus::NotHeld),
+            status => Err(SyncError::UnexpectedStatus(status)),
+        }
+    }
+}
+
+
+/// The transport `Lock` and `Election` use to reach the sidecar.
+///
+/// Implement it for custom transports and test doubles, and pass them to
+/// `LockBuilder::client` or `LockBuilder::shared_client`. Locks are named by
+/// their full URI, e.g. `http://localhost:8080/locks/some-lock`.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::collections::HashSet;
+/// use std::sync::Mutex;
+///
+/// use sync::{AcquireStatus, LockClient, LockStatus, SyncError};
+///
+/// #[derive(Debug, Default)]
+/// struct InProcess(Mutex<HashSet<String>>);
+///
+/// impl LockClient for InProcess {
+///     fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError> {
+///         match self.0.lock().unwrap().contains(lock) {
+///             true => Ok(LockStatus::Held(None)),
+///             false => Ok(LockStatus::Free),
+///         }
+///     }
+///
+///     fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError> {
+///         self.0.lock().unwrap().insert(lock.to_string());
+///         Ok(AcquireStatus::Acquired)
+///     }
+/// }
+///
+/// fn main() {
+///     let lock = sync::Lock::builder("some-lock")
+///                    .client(InProcess::default())
+///                    .build()
+///                    .expect("a valid lock configuration");
+///
+///     assert!(lock.lock(|| {}).is_ok());
+/// }
+/// ```
+pub trait LockClient: Debug + Send + Sync {
+    fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError>;
+
+    /// Acquires the lock, or renews it when it's already held by the caller.
+    fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError>;
+
+    /// Releases a held lock. Clients for sidecars that can't release locks
+    /// answer `Unsupported`, leaving the lock to expire with its TTL.
+    fn delete_lock(&self, _lock: &str) -> Result<ReleaseStatus, SyncError> {
+        Ok(ReleaseStatus::Unsupported)
+    }
+}
+
+
+/// The original client interface, in terms of the sidecar's HTTP statuses.
+///
+/// Every `MockableLockClient` is a `LockClient`, with 200 and 404 answers to
+/// `get_lock`, 200 and 409 answers to `put_lock` and 200, 204, 404, 405 and
+/// 409 answers to `delete_lock` understood (404 meaning the sidecar has no
+/// DELETE route), and any other status reported as
+/// `SyncError::UnexpectedStatus`. New clients should implement `LockClient`.
+pub trait MockableLockClient: Debug+Send+Sync {
+    fn get_lock(&self, &str) -> Result<StatusCode, SyncError>;
+    fn put_lock(&self, &str) -> Result<StatusCode, SyncError>;
+
+    /// Releases a held lock. Clients for sidecars that can't release locks
+    /// answer `MethodNotAllowed`, leaving the lock to expire with its TTL.
+    fn delete_lock(&self, _lock: &str) -> Result<StatusCode, SyncError> {
+        Ok(StatusCode::MethodNotAllowed)
+    }
+
+    /// Like `get_lock`, but also describes the lock's holder for sidecars
+    /// that report it. Clients that can't tell answer `None`.
+    fn get_lock_info(&self, lock: &str) -> Result<(StatusCode, Option<LockHolder>), SyncError> {
+        Ok((self.get_lock(lock)?, None))
+    }
+}
+
+impl<T: MockableLockClient> LockClient for T {
+    fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError> {
+        let (status, holder) = self.get_lock_info(lock)?;
+        LockStatus::from_status(status, holder)
+    }
+
+    fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError> {
+        AcquireStatus::from_status(MockableLockClient::put_lock(self, lock)?)
+    }
+
+    fn delete_lock(&self, lock: &str) -> Result<ReleaseStatus, SyncError> {
+        ReleaseStatus::from_status(MockableLockClient::delete_lock(self, lock)?)
+    }
+}
+
+
+/// The holder of a lock, as reported by the sidecar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+    id: String,
+    acquired_at: Option<SystemTime>,
+    expires_at: Option<SystemTime>,
+}
+
+impl LockHolder {
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        LockHolder{
+            id: id.into(),
+            acquired_at: None,
+            expires_at: None,
+        }
+    }
+
+    pub fn acquired_at(mut self, at: SystemTime) -> Self {
+        self.acquired_at = Some(at);
+        self
+    }
+
+    pub fn expires_at(mut self, at: SystemTime) -> Self {
+        self.expires_at = Some(at);
+        self
+    }
+
+    /// The holder id the lock was acquired with.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn acquired(&self) -> Option<SystemTime> {
+        self.acquired_at
+    }
+
+    /// When the lock expires unless it's renewed.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    // Reads sidecars' `{"holder": ..., "acquired_at": ..., "expires_at": ...}`
+    // bodies, with the times in milliseconds since the Unix epoch.
+    pub(crate) fn parse(body: &str) -> Option<Self> {
+        let value = json::parse(body).ok()?;
+        let mut holder = LockHolder::new(value["holder"].as_str()?);
+
+        holder.acquired_at = value["acquired_at"].as_u64().map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+        holder.expires_at = value["expires_at"].as_u64().map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+        Some(holder)
+    }
+}
+
+
+/// The HTTP client for the sidecar, which identifies the holder and asks for
+/// a TTL when renewing.
+#[derive(Debug, Default)]
+pub(crate) struct Client {
+    pub(crate) holder_id: Option<String>,
+    pub(crate) ttl: Option<Duration>,
+}
+impl Client {
+    fn query(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(ref holder_id) = self.holder_id {
+            params.push(format!("holder={}", percent_encode(holder_id)));
+        }
+        if let Some(ttl) = self.ttl {
+            params.push(format!("ttl_ms={}", as_millis(ttl)));
+        }
+
+        if params.is_empty() {
+            return String::new()
+        }
+        format!("?{}", params.join("&"))
+    }
+}
+
+// Escapes everything but unreserved characters, so that any holder id
+// survives the trip through the query string.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl Client {
+    pub(crate) fn check_uri(lock: &str) -> Result<(), SyncError> {
+        if lock.starts_with("http://") || lock.starts_with("https://") {
+            return Ok(())
+        }
+        Err(SyncError::InvalidUri(lock.to_string()))
+    }
+}
+
+impl MockableLockClient for Client {
+    fn get_lock(&self, lock: &str) -> Result<StatusCode, SyncError> {
+        Client::check_uri(lock)?;
+        match get(lock) {
+            Ok(response) => Ok(response.status_code()),
+            Err(error)   => Err(SyncError::from(error)),
+        }
+    }
+
+    fn get_lock_info(&self, lock: &str) -> Result<(StatusCode, Option<LockHolder>), SyncError> {
+        Client::check_uri(lock)?;
+        match get(lock) {
+            Ok(response) => {
+                let holder = response.text().and_then(LockHolder::parse);
+                Ok((response.status_code(), holder))
+            },
+            Err(error) => Err(SyncError::from(error)),
+        }
+    }
+
+    fn put_lock(&self, lock: &str) -> Result<StatusCode, SyncError> {
+        Client::check_uri(lock)?;
+        match put(&format!("{}{}", lock, self.query())) {
+            Ok(response) => Ok(response.status_code()),
+            Err(error)   => Err(SyncError::from(error)),
+        }
+    }
+
+    fn delete_lock(&self, lock: &str) -> Result<StatusCode, SyncError> {
+        Client::check_uri(lock)?;
+        match delete(&format!("{}{}", lock, self.query())) {
+            Ok(response) => Ok(response.status_code()),
+            Err(error)   => Err(SyncError::from(error)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use requests::StatusCode;
+
+    use client::{AcquireStatus, Client, LockClient, LockHolder, LockStatus, MockableLockClient, ReleaseStatus};
+    use error::SyncError;
+
+    #[derive(Debug)]
+    struct StatusClient(StatusCode);
+    impl MockableLockClient for StatusClient {
+        fn get_lock(&self, _: &str) -> Result<StatusCode, SyncError> {
+            Ok(self.0)
+        }
+
+        fn put_lock(&self, _: &str) -> Result<StatusCode, SyncError> {
+            Ok(self.0)
+        }
+
+        fn delete_lock(&self, _: &str) -> Result<StatusCode, SyncError> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_mapping_statuses() {
+        let client = StatusClient(StatusCode::Ok);
+        assert_eq!(LockClient::get_lock(&client, "lock").unwrap(), LockStatus::Held(None));
+        assert_eq!(LockClient::put_lock(&client, "lock").unwrap(), AcquireStatus::Acquired);
+        assert_eq!(LockClient::delete_lock(&client, "lock").unwrap(), ReleaseStatus::Released);
+
+        let client = StatusClient(StatusCode::NotFound);
+        assert_eq!(LockClient::get_lock(&client, "lock").unwrap(), LockStatus::Free);
+        assert_eq!(LockClient::delete_lock(&client, "lock").unwrap(), ReleaseStatus::Unsupported);
+
+        let client = StatusClient(StatusCode::Conflict);
+        assert_eq!(LockClient::put_lock(&client, "lock").unwrap(), AcquireStatus::Conflict);
+        assert_eq!(LockClient::delete_lock(&client, "lock").unwrap(), ReleaseStatus::NotHeld);
+
+        let client = StatusClient(StatusCode::ServiceUnavailable);
+        match LockClient::get_lock(&client, "lock") {
+            Err(SyncError::UnexpectedStatus(StatusCode::ServiceUnavailable)) => {},
+            other => panic!("expected an unexpected status error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parsing_lock_holders() {
+        let holder = LockHolder::parse(r#"{"holder": "replica-1", "acquired_at": 1000, "expires_at": 16000}"#);
+        assert_eq!(holder, Some(LockHolder::new("replica-1")
+                                    .acquired_at(UNIX_EPOCH + Duration::from_secs(1))
+                                    .expires_at(UNIX_EPOCH + Duration::from_secs(16))));
+
+        assert_eq!(LockHolder::parse(r#"{"holder": "replica-1"}"#), Some(LockHolder::new("replica-1")));
+        assert_eq!(LockHolder::parse(r#"{"acquired_at": 1000}"#), None);
+        assert_eq!(LockHolder::parse(""), None);
+    }
+
+    #[test]
+    fn test_encoding_query() {
+        let client = Client{
+            holder_id: Some("replica 1&ttl_ms=0#é".to_string()),
+            ttl: Some(Duration::from_millis(1500)),
+        };
+        assert_eq!(client.query(), "?holder=replica%201%26ttl_ms%3D0%23%C3%A9&ttl_ms=1500");
+
+        let client = Client{ holder_id: None, ttl: None };
+        assert_eq!(client.query(), "");
+    }
+}
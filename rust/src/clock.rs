@@ -0,0 +1,72 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::fmt::Debug;
+use std::sync::{Condvar, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+
+/// The time a `Lock` heartbeats and retries by.
+///
+/// Locks use the `SystemClock` unless they're built with another, such as
+/// the `ManualClock` of the `testing` feature, which only moves when it's
+/// advanced.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall-clock time, for reporting when locks were acquired and
+    /// expire.
+    fn system_time(&self) -> SystemTime;
+
+    /// Blocks the calling thread for `duration` of this clock's time. It may
+    /// return early, so callers waiting for a point in time should check
+    /// `now` again.
+    fn sleep(&self, duration: Duration);
+
+    /// Blocks on `condition` for `duration` of this clock's time, or until
+    /// it's notified. Like `sleep`, it may return early.
+    fn wait_timeout<'a>(&self, condition: &Condvar, guard: MutexGuard<'a, ()>, duration: Duration)
+                        -> MutexGuard<'a, ()> {
+        condition.wait_timeout(guard, duration).unwrap().0
+    }
+}
+
+
+/// The operating system's clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+
+/// Sleeps on `clock` until `deadline`, or until `interrupted` answers true.
+pub(crate) fn sleep_until<F>(clock: &Clock, deadline: Instant, interrupted: F)
+where F: Fn() -> bool
+{
+    loop {
+        let now = clock.now();
+        if now >= deadline || interrupted() {
+            return
+        }
+        clock.sleep(deadline - now);
+    }
+}
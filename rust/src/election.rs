@@ -7,10 +7,22 @@
 // except according to those terms.
 //
 
-use std::sync::Arc;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant, SystemTime};
 
+#[cfg(feature = "async")]
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+use cancel::CancellationToken;
+use client::{self, LockStatus};
+use clock;
+use error::SyncError;
 use lock;
+use retry::{Backoff, RetryPolicy};
 
 
 /// Helper macro for invoking election synchronization
@@ -58,22 +70,20 @@ macro_rules! elect {
         election.add_handler($crate::Handler::Follower, Box::new($followerfn));
         election
     }};
-    ($name: tt, $client:tt) => {{
-        use std::sync::Arc;
-
-        let mut election = elect!($name);
-        election.lock.client = Arc::new($client);
-        election
-    }};
-    ($name: tt, $client:tt, $leaderfn:expr, $followerfn:expr) => {{
-        use std::sync::Arc;
-
-        let mut election = elect!($name);
-        election.add_handler($crate::Handler::Leader  , Box::new($leaderfn));
-        election.add_handler($crate::Handler::Follower, Box::new($followerfn));
-        election.lock.client = Arc::new($client);
-        election
-    }};
+    ($name: tt, $client:tt) => (
+        $crate::Election::builder($name)
+            .lock_builder($crate::Lock::builder($name).client($client))
+            .build()
+            .expect("the default election configuration is valid")
+    );
+    ($name: tt, $client:tt, $leaderfn:expr, $followerfn:expr) => (
+        $crate::Election::builder($name)
+            .lock_builder($crate::Lock::builder($name).client($client))
+            .leader($leaderfn)
+            .follower($followerfn)
+            .build()
+            .expect("the default election configuration is valid")
+    );
 }
 
 
@@ -84,7 +94,7 @@ macro_rules! elect {
 ///
 /// # Example
 ///
-/// ```
+/// ```no_run
 /// extern crate metaparticle_sync as sync;
 ///
 /// #[derive(Copy,Clone)]
@@ -117,8 +127,9 @@ macro_rules! elect {
 ///
 ///     // ... Do other stuff
 ///
-///     elector.run();
-///
+///     if let Err(err) = elector.run() {
+///         println!("Election failed: {}", err);
+///     }
 /// }
 /// ```
 
@@ -126,139 +137,823 @@ macro_rules! elect {
 pub struct Election<'a> {
     lock: lock::Lock,
     running: Arc<AtomicBool>,
+    wake: Arc<(Mutex<()>, Condvar)>,
+    token: Arc<Mutex<CancellationToken>>,
+    resigned: Arc<AtomicBool>,
+    cooldown: Arc<Mutex<Option<Instant>>>,
+    tenure: Arc<(Mutex<Option<ThreadId>>, Condvar)>,
 
-    leader_fn: Arc<Box<Fn() -> () + Send + Sync + 'a>>,
-    follower_fn: Arc<Box<Fn() -> () + Send + Sync + 'a>>,
+    leader_fn: Arc<HandlerFn<'a>>,
+    follower_fn: Arc<HandlerFn<'a>>,
+    on_start: Option<Arc<HookFn<'a>>>,
+    on_stop: Option<Arc<HookFn<'a>>>,
+    on_elected: Option<Arc<HookFn<'a>>>,
+    on_demoted: Option<Arc<HookFn<'a>>>,
+    on_leader_changed: Option<Arc<LeaderChangedFn<'a>>>,
+    leadership: Arc<Mutex<Leadership>>,
+    subscribers: Arc<Subscribers>,
+    observing: bool,
 }
 
+type HandlerFn<'a> = Box<Fn(CancellationToken) -> () + Send + Sync + 'a>;
+type HookFn<'a> = Box<Fn() -> () + Send + Sync + 'a>;
+type LeaderChangedFn<'a> = Box<Fn(Option<LeaderId>) -> () + Send + Sync + 'a>;
+
 pub enum Handler {
     Leader,
     Follower,
+    /// Runs once each time this replica gains leadership, before the leader
+    /// handler.
+    Elected,
+    /// Runs once each time this replica loses leadership, after the leader
+    /// handler has returned.
+    Demoted,
+}
+
+/// Identifies the replica leading an election by the holder id of its lock.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LeaderId(String);
+
+impl LeaderId {
+    pub fn new<T: Into<String>>(id: T) -> Self {
+        LeaderId(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for LeaderId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The leader of an election, as last reported by the sidecar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderInfo {
+    id: LeaderId,
+    acquired_at: Option<SystemTime>,
+    lease_expiry: Option<SystemTime>,
+}
+
+impl LeaderInfo {
+    pub fn id(&self) -> &LeaderId {
+        &self.id
+    }
+
+    /// When the leader acquired the lock, if the sidecar reports it.
+    pub fn acquired_at(&self) -> Option<SystemTime> {
+        self.acquired_at
+    }
+
+    /// When the leader's lease runs out unless it's renewed, if the sidecar
+    /// reports it.
+    pub fn lease_expiry(&self) -> Option<SystemTime> {
+        self.lease_expiry
+    }
+}
+
+impl<'a> From<&'a client::LockHolder> for LeaderInfo {
+    fn from(holder: &client::LockHolder) -> Self {
+        LeaderInfo{
+            id: LeaderId::new(holder.id()),
+            acquired_at: holder.acquired(),
+            lease_expiry: holder.expires(),
+        }
+    }
+}
+
+/// Changes in an election, as sent to `Election::subscribe` receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeadershipEvent {
+    /// This replica started campaigning, or resumed after leading.
+    Campaigning,
+    /// This replica gained leadership.
+    Elected,
+    /// This replica's heartbeat renewed its leadership.
+    Renewed,
+    /// This replica lost or gave up leadership.
+    Demoted,
+    /// A leader was identified, possibly this replica.
+    LeaderObserved(LeaderId),
+    /// `Election::run` returned.
+    Stopped,
+}
+
+enum Subscriber {
+    Channel(Sender<LeadershipEvent>),
+    #[cfg(feature = "async")]
+    Stream(UnboundedSender<LeadershipEvent>),
+}
+
+#[derive(Default)]
+struct Subscribers(Mutex<Vec<Subscriber>>);
+
+impl Subscribers {
+    fn notify(&self, event: LeadershipEvent) {
+        self.0.lock().unwrap().retain(|subscriber| match *subscriber {
+            Subscriber::Channel(ref sender) => sender.send(event.clone()).is_ok(),
+            #[cfg(feature = "async")]
+            Subscriber::Stream(ref sender) => sender.unbounded_send(event.clone()).is_ok(),
+        });
+    }
+}
+
+// Who leads, as far as this replica can tell. The transition callbacks run
+// with it locked, so that they're serialized.
+#[derive(Default)]
+struct Leadership {
+    leading: bool,
+    leader: Option<LeaderId>,
 }
 
 impl<'a> Election<'a> {
     pub fn new<T: Into<String>>(name: T, base_uri: T,
                                 leader_fn: Box<Fn() -> () + Send + Sync + 'a>,
                                 follower_fn: Box<Fn() -> () + Send + Sync + 'a>) -> Self {
-        Election{
-            lock: lock::Lock::new(name, base_uri, 10),
-            running: Arc::new(AtomicBool::new(false)),
-            leader_fn: Arc::new(leader_fn),
-            follower_fn: Arc::new(follower_fn),
+        let name = name.into();
+        Election::builder(name.clone())
+            .lock(lock::Lock::new(name, base_uri.into(), 10))
+            .leader(move || leader_fn())
+            .follower(move || follower_fn())
+            .build()
+            .expect("an election with a configured lock always builds")
+    }
+
+    /// Starts building an election campaigning for the lock `name`.
+    pub fn builder<T: Into<String>>(name: T) -> ElectionBuilder<'a> {
+        ElectionBuilder{
+            lock: LockSource::Builder(lock::Lock::builder(name)),
+            leader_fn: Box::new(|_| {}),
+            follower_fn: Box::new(|_| {}),
+            on_start: None,
+            on_stop: None,
+            on_elected: None,
+            on_demoted: None,
+            on_leader_changed: None,
+            observing: false,
         }
     }
 
+    /// Starts building an observer of the election for the lock `name`.
+    ///
+    /// Observers never campaign, and only ever read the lock from the
+    /// sidecar. They poll it every heartbeat interval to report the leader
+    /// through `leader`, `on_leader_changed` and `subscribe`, and run the
+    /// follower handler once when they start.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// extern crate metaparticle_sync as sync;
+    ///
+    /// fn main() {
+    ///     let observer = sync::Election::observe("database-migration")
+    ///                        .on_leader_changed(|leader| println!("Leader is now {:?}", leader))
+    ///                        .build()
+    ///                        .expect("a valid election configuration");
+    ///
+    ///     if let Err(err) = observer.run() {
+    ///         println!("Observing failed: {}", err);
+    ///     }
+    /// }
+    /// ```
+    pub fn observe<T: Into<String>>(name: T) -> ElectionBuilder<'a> {
+        let mut builder = Election::builder(name);
+        builder.observing = true;
+        builder
+    }
+
     pub fn is_running(&self) -> bool {
         self.running.load(Ordering::Relaxed)
     }
 
-    pub fn run(&self) {
+    /// The current leader, for followers to forward requests to. It's
+    /// refreshed by the leader's heartbeat and each follower's campaign, so
+    /// it's `None` until the election has run, and when the sidecar doesn't
+    /// name holders.
+    pub fn leader(&self) -> Option<LeaderInfo> {
+        self.lock.holder().as_ref().map(LeaderInfo::from)
+    }
+
+    /// Campaigns for leadership until `shutdown` is called, running the
+    /// leader handler whenever elected and the follower handler whenever the
+    /// process becomes a follower, including after leading. Followers
+    /// re-campaign every heartbeat interval, taking over once the leader
+    /// releases the lock or lets it expire. Failing to reach the sidecar is
+    /// logged and retried, backing off by the lock's retry policy; only
+    /// errors that retrying can't fix stop the election and are returned.
+    ///
+    /// The transition callbacks all run on this thread, one at a time: each
+    /// `on_elected` is followed by the leader handler and then `on_demoted`
+    /// before this replica can be elected again, and `on_leader_changed`
+    /// follows `on_elected` and `on_demoted`.
+    pub fn run(&self) -> Result<(), SyncError> {
+        if let Some(ref on_start) = self.on_start {
+            on_start();
+        }
+        let result = if self.observing { self.watch() } else { self.campaign() };
+        if let Some(ref on_stop) = self.on_stop {
+            on_stop();
+        }
+        self.subscribers.notify(LeadershipEvent::Stopped);
+        result
+    }
+
+    fn campaign(&self) -> Result<(), SyncError> {
         let leader_fn = self.leader_fn.clone();
         let follower_fn = self.follower_fn.clone();
         self.running.store(true, Ordering::Relaxed);
+
+        self.subscribers.notify(LeadershipEvent::Campaigning);
+
+        let mut backoff = None;
+        let mut following = false;
         while self.is_running() {
-            self.lock.lock(|| leader_fn());
+            if let Some(cooldown) = self.cooling_down() {
+                self.pause(cooldown);
+                continue
+            }
+
+            // Demoting before the lock is released keeps another replica
+            // from being elected while this one still leads. Whatever the
+            // lock's retry policy, campaigning never waits for the lock, as
+            // the follower runs meanwhile and it's asked for again anyway.
+            let result = self.lock.lock_cancellable_with_policy(RetryPolicy::no_retry(), |token| {
+                self.begin_tenure();
+                self.elected();
+                leader_fn(self.track(token));
+                self.demoted();
+            });
+            let resigned = self.end_tenure();
 
-            follower_fn();
-            break;
+            match result {
+                Ok(()) => {
+                    if resigned {
+                        info!("Resigned leadership of {}", election: self.lock.name());
+                    }
+                    following = false;
+                },
+                Err(SyncError::Held) => self.observe_holder(self.lock.holder()),
+                Err(SyncError::LockLost) => {
+                    warn!("Lost leadership of {}", election: self.lock.name());
+                    following = false;
+                },
+                Err(ref err) if err.is_retryable() => {
+                    warn!("Could not campaign for {}: {}",
+                          election: self.lock.name(),
+                          error: err.to_string());
+                    self.pause(self.retry_delay(&mut backoff));
+                    continue
+                },
+                Err(err) => {
+                    self.shutdown();
+                    return Err(err)
+                },
+            }
+            backoff = None;
+
+            if !self.is_running() {
+                break
+            }
+            if !following {
+                following = true;
+                if self.follow(&follower_fn) {
+                    continue
+                }
+            }
+            self.pause(self.lock.heartbeat_interval());
         }
+        Ok(())
     }
 
+    fn watch(&self) -> Result<(), SyncError> {
+        self.running.store(true, Ordering::Relaxed);
+        (self.follower_fn)(self.track(CancellationToken::new()));
+
+        let mut backoff = None;
+        while self.is_running() {
+            let delay = match self.lock.watch() {
+                Ok(_) => {
+                    backoff = None;
+                    self.observe_holder(self.lock.holder());
+                    self.lock.heartbeat_interval()
+                },
+                Err(ref err) if err.is_retryable() => {
+                    warn!("Could not observe {}: {}",
+                          election: self.lock.name(),
+                          error: err.to_string());
+                    self.retry_delay(&mut backoff)
+                },
+                Err(err) => {
+                    self.shutdown();
+                    return Err(err)
+                },
+            };
+            self.pause(delay);
+        }
+        Ok(())
+    }
+
+    // Runs the follower handler, cancelling its token as soon as the lock is
+    // free, so that a long-running follower doesn't hold up failover.
+    // Returns whether the lock was found free.
+    fn follow(&self, follower_fn: &HandlerFn<'a>) -> bool {
+        let token = self.track(CancellationToken::new());
+        let vacated = Arc::new(AtomicBool::new(false));
+
+        let (lock, watching, found) = (self.lock.clone(), token.clone(), vacated.clone());
+        thread::spawn(move || {
+            let clock = lock.clock();
+            while !watching.is_cancelled() {
+                let next = clock.now() + lock.heartbeat_interval();
+                clock::sleep_until(&*clock, next, || watching.is_cancelled());
+                if watching.is_cancelled() {
+                    break
+                }
+                if let Ok(LockStatus::Free) = lock.watch() {
+                    found.store(true, Ordering::Relaxed);
+                    watching.cancel();
+                }
+            }
+        });
+
+        follower_fn(token.clone());
+        token.cancel();
+        vacated.load(Ordering::Relaxed)
+    }
+
+    // How long to wait before trying the sidecar again after failing to
+    // reach it, backing off by the lock's retry policy, and by heartbeat
+    // intervals once the policy gives up.
+    fn retry_delay(&self, backoff: &mut Option<Backoff>) -> Duration {
+        let lock = &self.lock;
+        let delay = backoff.get_or_insert_with(|| lock.retry_policy().backoff(lock.clock()))
+                           .next_delay();
+        match delay {
+            Some(delay) => delay,
+            None => {
+                *backoff = None;
+                lock.heartbeat_interval()
+            },
+        }
+    }
+
+    fn observe_holder(&self, holder: Option<client::LockHolder>) {
+        let mut leadership = self.leadership.lock().unwrap();
+        self.change_leader(&mut leadership, holder.map(|holder| LeaderId::new(holder.id())));
+    }
+
+    fn elected(&self) {
+        let mut leadership = self.leadership.lock().unwrap();
+        if !leadership.leading {
+            leadership.leading = true;
+            if let Some(ref on_elected) = self.on_elected {
+                on_elected();
+            }
+            self.subscribers.notify(LeadershipEvent::Elected);
+        }
+        let leader = self.lock.holder().map(|holder| LeaderId::new(holder.id()));
+        self.change_leader(&mut leadership, leader);
+    }
+
+    fn demoted(&self) {
+        let mut leadership = self.leadership.lock().unwrap();
+        if leadership.leading {
+            leadership.leading = false;
+            if let Some(ref on_demoted) = self.on_demoted {
+                on_demoted();
+            }
+            self.subscribers.notify(LeadershipEvent::Demoted);
+            self.change_leader(&mut leadership, None);
+
+            if self.is_running() {
+                self.subscribers.notify(LeadershipEvent::Campaigning);
+            }
+        }
+    }
+
+    fn change_leader(&self, leadership: &mut Leadership, leader: Option<LeaderId>) {
+        if leadership.leader != leader {
+            leadership.leader = leader.clone();
+            if let Some(ref id) = leader {
+                self.subscribers.notify(LeadershipEvent::LeaderObserved(id.clone()));
+            }
+            if let Some(ref on_leader_changed) = self.on_leader_changed {
+                on_leader_changed(leader);
+            }
+        }
+    }
+
+    // How much longer a resignation keeps this replica from campaigning.
+    fn cooling_down(&self) -> Option<Duration> {
+        let now = self.lock.clock().now();
+        let mut cooldown = self.cooldown.lock().unwrap();
+        match *cooldown {
+            Some(until) if until > now => Some(until - now),
+            _ => {
+                *cooldown = None;
+                None
+            },
+        }
+    }
+
+    // Waits before campaigning again, waking early on `shutdown`.
+    fn pause(&self, timeout: Duration) {
+        let clock = self.lock.clock();
+        let deadline = clock.now() + timeout;
+        let &(ref mutex, ref condition) = &*self.wake;
+        let mut guard = mutex.lock().unwrap();
+        while self.is_running() {
+            let now = clock.now();
+            if now >= deadline {
+                return
+            }
+            guard = clock.wait_timeout(condition, guard, deadline - now);
+        }
+    }
+
+    // Makes `token` the one cancelled by `shutdown`, cancelling it straight
+    // away if the election has already been shut down or resigned.
+    fn track(&self, token: CancellationToken) -> CancellationToken {
+        let mut tracked = self.token.lock().unwrap();
+        *tracked = token.clone();
+        if !self.is_running() || self.resigned.load(Ordering::Relaxed) {
+            token.cancel();
+        }
+        token
+    }
+
+    // Marks this thread as leading, until `end_tenure`.
+    fn begin_tenure(&self) {
+        let &(ref mutex, _) = &*self.tenure;
+        *mutex.lock().unwrap() = Some(thread::current().id());
+    }
+
+    // Marks the lock as released, waking `step_down`, and returns whether
+    // leadership was resigned.
+    fn end_tenure(&self) -> bool {
+        let &(ref mutex, ref condition) = &*self.tenure;
+        let mut leader = mutex.lock().unwrap();
+        *leader = None;
+        condition.notify_all();
+        self.resigned.swap(false, Ordering::Relaxed)
+    }
+
+    // Cancels the leader handler if this replica is leading, and waits for
+    // it to return and for the lock to be released, unless it's the leader
+    // handler itself stepping down.
+    fn step_down(&self) {
+        let &(ref mutex, ref condition) = &*self.tenure;
+        let mut leader = mutex.lock().unwrap();
+        if leader.is_none() && !self.lock.is_locked() {
+            return
+        }
+
+        self.resigned.store(true, Ordering::Relaxed);
+        self.token.lock().unwrap().cancel();
+
+        let current = Some(thread::current().id());
+        while *leader != current && (leader.is_some() || self.lock.is_locked()) {
+            leader = condition.wait(leader).unwrap();
+        }
+    }
+
+    /// Returns a receiver for every subsequent change in the election.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// extern crate metaparticle_sync as sync;
+    ///
+    /// use std::thread;
+    ///
+    /// fn main() {
+    ///     let elector = sync::Election::builder("database-migration")
+    ///                       .build()
+    ///                       .expect("a valid election configuration");
+    ///     let events = elector.subscribe();
+    ///
+    ///     let running = elector.clone();
+    ///     thread::spawn(move || running.run());
+    ///
+    ///     for event in events.iter() {
+    ///         match event {
+    ///             sync::LeadershipEvent::LeaderObserved(leader) => println!("{} leads", leader),
+    ///             sync::LeadershipEvent::Stopped => break,
+    ///             _ => {},
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe(&self) -> Receiver<LeadershipEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.0.lock().unwrap().push(Subscriber::Channel(sender));
+        receiver
+    }
+
+    /// Like `subscribe`, but returns a `Stream` of the election's changes.
+    #[cfg(feature = "async")]
+    pub fn subscribe_stream(&self) -> UnboundedReceiver<LeadershipEvent> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.0.lock().unwrap().push(Subscriber::Stream(sender));
+        receiver
+    }
+
+    /// Steps down if this replica is leading, and keeps it from campaigning
+    /// again for a heartbeat interval, so that another replica can take over.
+    pub fn resign(&self) {
+        self.resign_for(self.lock.heartbeat_interval());
+    }
+
+    /// Like `resign`, but keeps this replica from campaigning for `cooldown`.
+    ///
+    /// The leader handler's token is cancelled, and once the handler has
+    /// returned the lock is released on the sidecar, without waiting for its
+    /// TTL to run out. Unless called from the leader handler, this blocks
+    /// until the lock has been released.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// extern crate metaparticle_sync as sync;
+    ///
+    /// use std::time::Duration;
+    ///
+    /// fn main() {
+    ///     let elector = sync::Election::builder("database-migration")
+    ///                       .build()
+    ///                       .expect("a valid election configuration");
+    ///
+    ///     // .. on SIGTERM, during a rolling deploy
+    ///     elector.resign_for(Duration::from_secs(30));
+    /// }
+    /// ```
+    pub fn resign_for(&self, cooldown: Duration) {
+        *self.cooldown.lock().unwrap() = Some(self.lock.clock().now() + cooldown);
+        self.step_down();
+    }
+
+    /// Stops the election, cancelling the token of the running handler, and
+    /// resigning if this replica is leading. Like `resign_for`, this blocks
+    /// until the leader handler has returned and the lock been released.
     pub fn shutdown(&self) {
         self.running.store(false, Ordering::Relaxed);
+        {
+            let &(ref mutex, ref condition) = &*self.wake;
+            let _guard = mutex.lock().unwrap();
+            condition.notify_all();
+        }
+        self.token.lock().unwrap().cancel();
+        self.step_down();
     }
 
     pub fn add_handler(&mut self, typ: Handler, handler: Box<Fn() -> () + Send + Sync + 'a>) {
+        match typ {
+            Handler::Elected => self.on_elected = Some(Arc::new(handler)),
+            Handler::Demoted => self.on_demoted = Some(Arc::new(handler)),
+            typ => self.add_cancellable_handler(typ, Box::new(move |_| handler())),
+        }
+    }
+
+    /// Like `add_handler`, but the handler is passed a token that's cancelled
+    /// when leadership is lost or the election is shut down, and for
+    /// followers, when the lock is free to campaign for. `Elected` and
+    /// `Demoted` handlers run to completion, so their token never is.
+    pub fn add_cancellable_handler(&mut self, typ: Handler, handler: HandlerFn<'a>) {
         match typ {
             Handler::Leader   => self.leader_fn = Arc::new(handler),
             Handler::Follower => self.follower_fn = Arc::new(handler),
-
+            typ => self.add_handler(typ, Box::new(move || handler(CancellationToken::new()))),
         };
     }
+
+    /// Runs `callback` with the new leader each time leadership changes
+    /// hands. Leaders are identified by the holder id of their lock, and
+    /// `None` stands for no leader, or one the sidecar doesn't name.
+    pub fn on_leader_changed<F>(&mut self, callback: F)
+    where F: Fn(Option<LeaderId>) -> () + Send + Sync + 'a
+    {
+        self.on_leader_changed = Some(Arc::new(Box::new(callback)));
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-    use std::thread::{self, sleep};
-    use std::time::{Duration, Instant};
 
-    use requests::{StatusCode, Error};
+/// Builds an `Election`.
+///
+/// The election campaigns through a `Lock` named after it, which can be
+/// configured with `lock_builder`, or replaced by a preconfigured one with
+/// `lock`. `on_start` and `on_stop` hooks run as `Election::run` begins and
+/// returns.
+///
+/// # Example
+///
+/// ```no_run
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let elector = sync::Election::builder("database-migration")
+///                       .lock_builder(sync::Lock::builder("database-migration")
+///                                         .heartbeat_interval(Duration::from_secs(2))
+///                                         .ttl(Duration::from_secs(6)))
+///                       .leader(|| {
+///                           // ... long-lasting work
+///                       })
+///                       .follower(|| {
+///                           // ... keep an eye on it.
+///                       })
+///                       .on_start(|| println!("Campaigning"))
+///                       .on_stop(|| println!("Stopped campaigning"))
+///                       .build()
+///                       .expect("a valid election configuration");
+///
+///     if let Err(err) = elector.run() {
+///         println!("Election failed: {}", err);
+///     }
+/// }
+/// ```
+pub struct ElectionBuilder<'a> {
+    lock: LockSource,
 
-    use lock::MockableLockClient;
+    leader_fn: HandlerFn<'a>,
+    follower_fn: HandlerFn<'a>,
+    on_start: Option<HookFn<'a>>,
+    on_stop: Option<HookFn<'a>>,
+    on_elected: Option<HookFn<'a>>,
+    on_demoted: Option<HookFn<'a>>,
+    on_leader_changed: Option<LeaderChangedFn<'a>>,
+    observing: bool,
+}
 
-    use election;
+impl<'a> ElectionBuilder<'a> {
+    /// Campaigns through an already configured `lock`.
+    pub fn lock(mut self, lock: lock::Lock) -> Self {
+        self.lock = LockSource::Configured(lock);
+        self
+    }
 
-    #[derive(Debug,Clone)]
-    struct MockLock((String, Instant));
+    /// Campaigns through the lock that `builder` builds.
+    pub fn lock_builder(mut self, builder: lock::LockBuilder) -> Self {
+        self.lock = LockSource::Builder(builder);
+        self
+    }
 
-    #[derive(Debug,Clone)]
-    struct MockLockServer(Arc<Mutex<HashMap<String, MockLock>>>);
-    impl MockLockServer {
-        fn new() -> Self {
-            MockLockServer(Arc::new(Mutex::new(HashMap::new())))
-        }
+    pub fn leader<F>(self, handler: F) -> Self
+    where F: Fn() -> () + Send + Sync + 'a
+    {
+        self.cancellable_leader(move |_| handler())
     }
 
-    #[derive(Debug,Clone)]
-    struct MockClient((String, MockLockServer));
+    pub fn follower<F>(self, handler: F) -> Self
+    where F: Fn() -> () + Send + Sync + 'a
+    {
+        self.cancellable_follower(move |_| handler())
+    }
 
-    impl PartialEq for MockClient {
-        fn eq(&self, rhs: &Self) -> bool {
-            (self.0).0 == (rhs.0).0
-        }
+    /// Like `leader`, but the handler is passed a token that's cancelled
+    /// when leadership is lost or the election is shut down.
+    pub fn cancellable_leader<F>(mut self, handler: F) -> Self
+    where F: Fn(CancellationToken) -> () + Send + Sync + 'a
+    {
+        self.leader_fn = Box::new(handler);
+        self
     }
 
-    impl MockClient {
-        fn new<T: Into<String>>(client: T, server: MockLockServer) -> Self {
-            MockClient((client.into(), server))
-        }
+    /// Like `follower`, but the handler is passed a token that's cancelled
+    /// when the election is shut down, or when the lock is free for this
+    /// replica to campaign for. Followers that run for long should return
+    /// once it's cancelled, as failover waits for them.
+    pub fn cancellable_follower<F>(mut self, handler: F) -> Self
+    where F: Fn(CancellationToken) -> () + Send + Sync + 'a
+    {
+        self.follower_fn = Box::new(handler);
+        self
+    }
+
+    /// Runs `hook` each time `Election::run` starts campaigning.
+    pub fn on_start<F>(mut self, hook: F) -> Self
+    where F: Fn() -> () + Send + Sync + 'a
+    {
+        self.on_start = Some(Box::new(hook));
+        self
     }
 
-    impl MockableLockClient for MockClient {
-        fn get_lock(&self, lock: &str) -> Result<StatusCode, Error> {
-            let &MockClient((_, ref mutex)) = self;
-            let locks = mutex.0.lock().unwrap();
+    /// Runs `hook` each time `Election::run` returns.
+    pub fn on_stop<F>(mut self, hook: F) -> Self
+    where F: Fn() -> () + Send + Sync + 'a
+    {
+        self.on_stop = Some(Box::new(hook));
+        self
+    }
 
-            if locks.contains_key(lock) {
-                return Ok(StatusCode::Ok);
-            }
-            Ok(StatusCode::NotFound)
-        }
+    /// Runs `hook` once each time this replica gains leadership, before the
+    /// leader handler.
+    pub fn on_elected<F>(mut self, hook: F) -> Self
+    where F: Fn() -> () + Send + Sync + 'a
+    {
+        self.on_elected = Some(Box::new(hook));
+        self
+    }
 
-        fn put_lock(&self, lock: &str) -> Result<StatusCode, Error> {
-            let &MockClient((ref client, ref mutex)) = self;
-            let mut locks = mutex.0.lock().unwrap();
+    /// Runs `hook` once each time this replica loses leadership, whether it
+    /// was lost, released or the election shut down, after the leader
+    /// handler has returned.
+    pub fn on_demoted<F>(mut self, hook: F) -> Self
+    where F: Fn() -> () + Send + Sync + 'a
+    {
+        self.on_demoted = Some(Box::new(hook));
+        self
+    }
 
-            match locks.get(lock) {
-                Some(mocklock) => {
-                    let &MockLock((ref lockclient, ref timeout)) = mocklock;
-                    let elapsed = Instant::now().duration_since(*timeout);
+    /// See `Election::on_leader_changed`.
+    pub fn on_leader_changed<F>(mut self, callback: F) -> Self
+    where F: Fn(Option<LeaderId>) -> () + Send + Sync + 'a
+    {
+        self.on_leader_changed = Some(Box::new(callback));
+        self
+    }
 
-                    if lockclient != &*client && elapsed < Duration::new(1, 0) {
-                        return Ok(StatusCode::Conflict)
-                    }
-                },
-                _ => {},
+    pub fn build(self) -> Result<Election<'a>, SyncError> {
+        let lock = match self.lock {
+            LockSource::Builder(builder) => builder.build()?,
+            LockSource::Configured(lock) => lock,
+        };
+
+        // Renewals happen on the lock's heartbeat thread.
+        let subscribers = Arc::new(Subscribers::default());
+        let renewals = subscribers.clone();
+        lock.on_event(move |event| {
+            if event == lock::LockEvent::Renewed {
+                renewals.notify(LeadershipEvent::Renewed);
             }
-            locks.insert(lock.to_string(), MockLock((client.to_string(), Instant::now())));
-            Ok(StatusCode::Ok)
-        }
+        });
+
+        Ok(Election{
+            lock: lock,
+            running: Arc::new(AtomicBool::new(false)),
+            wake: Arc::new((Mutex::new(()), Condvar::new())),
+            token: Arc::new(Mutex::new(CancellationToken::new())),
+            resigned: Arc::new(AtomicBool::new(false)),
+            cooldown: Arc::new(Mutex::new(None)),
+            tenure: Arc::new((Mutex::new(None), Condvar::new())),
+            leader_fn: Arc::new(self.leader_fn),
+            follower_fn: Arc::new(self.follower_fn),
+            on_start: self.on_start.map(Arc::new),
+            on_stop: self.on_stop.map(Arc::new),
+            on_elected: self.on_elected.map(Arc::new),
+            on_demoted: self.on_demoted.map(Arc::new),
+            on_leader_changed: self.on_leader_changed.map(Arc::new),
+            leadership: Arc::new(Mutex::new(Leadership::default())),
+            subscribers: subscribers,
+            observing: self.observing,
+        })
+    }
+}
+
+enum LockSource {
+    Builder(lock::LockBuilder),
+    Configured(lock::Lock),
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, sleep};
+    use std::time::Duration;
+
+    use rand::{thread_rng, Rng};
+    use requests::StatusCode;
+
+    use client::{AcquireStatus, LockClient, LockStatus};
+    use error::SyncError;
+    use lock::{Lock, LockBuilder, LockEvent};
+    use retry::RetryPolicy;
+    use testing::{Fault, FaultyClient, History, InMemoryLockClient, InMemoryLockServer, ManualClock};
+
+    use election::{self, LeaderId, LeadershipEvent};
+
+    fn new_server() -> InMemoryLockServer {
+        InMemoryLockServer::with_ttl(Duration::from_secs(1))
     }
 
     #[test]
     fn test_run_election_with_add_handler() {
-        let server = MockLockServer::new();
-        let client0 = MockClient::new("client0", server.clone());
-        let client1 = MockClient::new("client1", server.clone());
-        let client2 = MockClient::new("client2", server.clone());
+        let server = new_server();
+        let client0 = server.client("client0");
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
 
-        let expected = client0.clone();
+        let expected = client0.holder_id().to_string();
 
-        let leading: Arc<Mutex<Option<MockClient>>> = Arc::new(Mutex::new(None));
-        let mut mutexes: Vec<Arc<Mutex<Option<MockClient>>>> = (0..3).map(|_| leading.clone())
-                                                                     .collect();
+        let leading: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let mut mutexes: Vec<Arc<Mutex<Option<String>>>> = (0..3).map(|_| leading.clone())
+                                                                 .collect();
 
         let clients = vec![client0, client1, client2];
 
@@ -269,7 +964,7 @@ mod tests {
             let mut elector = elect!("fake-lock", clone);
             elector.add_handler(election::Handler::Leader,
                                 Box::new(move || {
-                                     *(mutex.lock()).unwrap() = Some(client.clone());
+                                     *(mutex.lock()).unwrap() = Some(client.holder_id().to_string());
                                      sleep(Duration::from_millis(250));
                                 }));
             elector.add_handler(election::Handler::Follower,
@@ -284,18 +979,610 @@ mod tests {
         assert_eq!(*leading.lock().unwrap(), Some(expected));
     }
 
+    #[test]
+    fn test_shutdown_cancels_leader() {
+        let server = new_server();
+        let client = server.client("client0");
+
+        let cancelled = Arc::new(Mutex::new(false));
+        let flag = cancelled.clone();
+
+        let mut elector = elect!("cancel-lock", client);
+        elector.add_cancellable_handler(election::Handler::Leader,
+                                        Box::new(move |token| {
+                                            while !token.is_cancelled() {
+                                                sleep(Duration::from_millis(50));
+                                            }
+                                            *flag.lock().unwrap() = true;
+                                        }));
+
+        let running = elector.clone();
+        let handle = thread::spawn(move || running.run());
+
+        sleep(Duration::from_millis(250));
+        elector.shutdown();
+
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(*cancelled.lock().unwrap(), true);
+    }
+
+    #[test]
+    fn test_building_election_with_hooks() {
+        let server = new_server();
+        let client = server.client("client0");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let (start, leader, stop) = (calls.clone(), calls.clone(), calls.clone());
+        let elector = election::Election::builder("hooked-lock")
+                          .lock_builder(Lock::builder("hooked-lock")
+                                            .heartbeat_interval(Duration::from_millis(100))
+                                            .client(client))
+                          .on_start(move || start.lock().unwrap().push("start"))
+                          .cancellable_leader(move |token| {
+                              leader.lock().unwrap().push("leader");
+                              while !token.is_cancelled() {
+                                  sleep(Duration::from_millis(10));
+                              }
+                          })
+                          .on_stop(move || stop.lock().unwrap().push("stop"))
+                          .build()
+                          .unwrap();
+
+        let running = elector.clone();
+        let handle = thread::spawn(move || running.run());
+
+        sleep(Duration::from_millis(250));
+        elector.shutdown();
+
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["start", "leader", "stop"]);
+    }
+
+    fn failover_lock<C: LockClient + 'static>(client: C) -> LockBuilder {
+        Lock::builder("failover-lock")
+            .heartbeat_interval(Duration::from_millis(100))
+            .client(client)
+    }
+
+    fn campaigner(name: &str, server: &InMemoryLockServer,
+                  leaders: Arc<Mutex<Vec<String>>>,
+                  followers: Arc<Mutex<Vec<String>>>) -> election::Election<'static> {
+        campaigner_with(name, failover_lock(server.client(name)), leaders, followers)
+    }
+
+    fn campaigner_with(name: &str, lock: LockBuilder,
+                       leaders: Arc<Mutex<Vec<String>>>,
+                       followers: Arc<Mutex<Vec<String>>>) -> election::Election<'static> {
+        let (leader, follower) = (name.to_string(), name.to_string());
+        election::Election::builder("failover-lock")
+            .lock_builder(lock)
+            .cancellable_leader(move |token| {
+                leaders.lock().unwrap().push(leader.clone());
+                while !token.is_cancelled() {
+                    sleep(Duration::from_millis(10));
+                }
+            })
+            .follower(move || followers.lock().unwrap().push(follower.clone()))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_failing_over_between_campaigners() {
+        let clock = ManualClock::new();
+        let server = new_server().clock(clock.clone());
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        let mut elections: HashMap<String, election::Election<'static>> = HashMap::new();
+        let mut handles = Vec::new();
+        for name in vec!["client0", "client1", "client2"] {
+            let lock = failover_lock(server.client(name)).clock(clock.clone());
+            let elector = campaigner_with(name, lock, leaders.clone(), followers.clone());
+            let running = elector.clone();
+            handles.push(thread::spawn(move || running.run()));
+            elections.insert(name.to_string(), elector);
+            sleep(Duration::from_millis(50));
+        }
+
+        sleep(Duration::from_millis(300));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0".to_string()]);
+        assert_eq!(followers.lock().unwrap().len(), 2);
+
+        // The leader shuts down, releasing its lock for one of the followers
+        // to take over, until nobody's left to.
+        for round in 1..3 {
+            let leader = leaders.lock().unwrap().last().unwrap().clone();
+            elections[&leader].shutdown();
+            clock.advance(Duration::from_millis(100));
+            sleep(Duration::from_millis(200));
+
+            let leaders = leaders.lock().unwrap();
+            assert_eq!(leaders.len(), round + 1);
+            assert!(!leaders[..round].contains(&leaders[round]));
+        }
+
+        for elector in elections.values() {
+            elector.shutdown();
+        }
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_following_whatever_the_retry_policy() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        // Retrying within the lock would fail or block the campaign, rather
+        // than let it follow while client0 leads.
+        let policies = vec![RetryPolicy::no_retry(), RetryPolicy::retries(2), RetryPolicy::forever()];
+        let elections: Vec<_> = policies.into_iter().enumerate().map(|(i, policy)| {
+            let name = format!("client{}", i);
+            let lock = failover_lock(server.client(name.as_str())).retry_policy(policy);
+            campaigner_with(&name, lock, leaders.clone(), followers.clone())
+        }).collect();
+        let handles: Vec<_> = elections.iter().map(|elector| {
+            let running = elector.clone();
+            let handle = thread::spawn(move || running.run());
+            sleep(Duration::from_millis(50));
+            handle
+        }).collect();
+
+        sleep(Duration::from_millis(500));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0"]);
+        assert_eq!(*followers.lock().unwrap(), vec!["client1", "client2"]);
+
+        for elector in &elections {
+            elector.shutdown();
+        }
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_failing_over_from_partitioned_leader() {
+        let clock = ManualClock::new();
+        let server = new_server().clock(clock.clone());
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        let partitioned = FaultyClient::new(server.client("client0"));
+        let leader = campaigner_with("client0", failover_lock(partitioned.clone()).clock(clock.clone()),
+                                     leaders.clone(), followers.clone());
+        let follower = campaigner_with("client1", failover_lock(server.client("client1")).clock(clock.clone()),
+                                       leaders.clone(), followers.clone());
+
+        let handles: Vec<_> = vec![leader.clone(), follower.clone()].into_iter().map(|elector| {
+            let handle = thread::spawn(move || elector.run());
+            sleep(Duration::from_millis(50));
+            handle
+        }).collect();
+
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0"]);
+
+        // The follower takes over once the leader's lock expires, and the
+        // leader steps down as soon as it can reach the sidecar again.
+        partitioned.partition();
+        clock.advance(server.ttl());
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0", "client1"]);
+
+        partitioned.heal();
+        clock.advance(Duration::from_millis(100));
+        sleep(Duration::from_millis(200));
+        assert_eq!(leader.leader().map(|info| info.id().clone()), Some(LeaderId::new("client1")));
+
+        leader.shutdown();
+        follower.shutdown();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_campaigning_through_sidecar_errors() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        let client = FaultyClient::new(server.client("client0"));
+        client.script(vec![Fault::ConnectionError,
+                           Fault::ServerError(StatusCode::ServiceUnavailable),
+                           Fault::ConnectionError]);
+        let elector = campaigner_with("client0", failover_lock(client), leaders.clone(), followers.clone());
+        let running = elector.clone();
+        let handle = thread::spawn(move || running.run());
+
+        sleep(Duration::from_millis(600));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0"]);
+
+        elector.shutdown();
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_failing_over_from_long_running_follower() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+
+        let elections: Vec<_> = vec!["client0", "client1"].into_iter().map(|name| {
+            let (leaders, leader) = (leaders.clone(), name.to_string());
+            election::Election::builder("patient-lock")
+                .lock_builder(Lock::builder("patient-lock")
+                                  .heartbeat_interval(Duration::from_millis(100))
+                                  .client(server.client(name)))
+                .cancellable_leader(move |token| {
+                    leaders.lock().unwrap().push(leader.clone());
+                    while !token.is_cancelled() {
+                        sleep(Duration::from_millis(10));
+                    }
+                })
+                .cancellable_follower(|token| {
+                    while !token.is_cancelled() {
+                        sleep(Duration::from_millis(10));
+                    }
+                })
+                .build()
+                .unwrap()
+        }).collect();
+        let handles: Vec<_> = elections.iter().map(|elector| {
+            let running = elector.clone();
+            let handle = thread::spawn(move || running.run());
+            sleep(Duration::from_millis(50));
+            handle
+        }).collect();
+
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0"]);
+
+        // The follower is still running when the leader steps down, and is
+        // cancelled for its replica to take over.
+        elections[0].shutdown();
+        sleep(Duration::from_millis(400));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0", "client1"]);
+
+        elections[1].shutdown();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_leading_exclusively_under_faults() {
+        let server = new_server();
+        let history = History::new();
+
+        let clients: Vec<_> = (0..3).map(|i| {
+            FaultyClient::new(server.client(format!("client{}", i))).connection_errors(0.05)
+        }).collect();
+        let elections: Vec<_> = clients.iter().enumerate().map(|(i, client)| {
+            let (history, leader) = (history.clone(), format!("client{}", i));
+            election::Election::builder("chaos-lock")
+                .lock_builder(Lock::builder("chaos-lock")
+                                  .heartbeat_interval(Duration::from_millis(100))
+                                  .ttl(server.ttl())
+                                  .client(client.clone()))
+                .cancellable_leader(move |token| {
+                    history.record_event("chaos-lock", &leader, LockEvent::Acquired);
+                    while !token.is_cancelled() {
+                        sleep(Duration::from_millis(10));
+                    }
+                    history.record_event("chaos-lock", &leader, LockEvent::Released);
+                })
+                .build()
+                .unwrap()
+        }).collect();
+        let handles: Vec<_> = elections.iter().map(|elector| {
+            let running = elector.clone();
+            thread::spawn(move || running.run())
+        }).collect();
+
+        // Partition and heal replicas at random while they campaign, and make
+        // whoever's leading every other round resign, so leadership changes
+        // hands under faults rather than settling on one replica.
+        let mut rng = thread_rng();
+        for round in 0..12 {
+            if round % 2 == 0 {
+                let i = rng.gen_range(0, clients.len());
+                if rng.gen() {
+                    clients[i].partition();
+                } else {
+                    clients[i].heal();
+                }
+            } else if let Some(holder) = server.holder("http://localhost:8080/locks/chaos-lock") {
+                let i: usize = holder.id()["client".len()..].parse().unwrap();
+                clients[i].heal();
+                elections[i].resign_for(Duration::from_millis(200));
+            }
+            sleep(Duration::from_millis(rng.gen_range(300, 500)));
+        }
+
+        for (client, elector) in clients.iter().zip(elections.iter()) {
+            client.heal();
+            elector.shutdown();
+        }
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+
+        let entries = history.entries();
+        let handovers = entries.iter().filter(|entry| entry.event() == LockEvent::Acquired).count();
+        assert!(handovers >= 4, "only {} terms in {:?}", handovers, entries);
+        if let Err(overlap) = history.check() {
+            panic!("{} in {:?}", overlap, entries);
+        }
+    }
+
+    #[test]
+    fn test_resigning_leadership() {
+        let clock = ManualClock::new();
+        let server = new_server().clock(clock.clone());
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        let elections: Vec<_> = vec!["client0", "client1"].into_iter().map(|name| {
+            let lock = failover_lock(server.client(name)).clock(clock.clone());
+            campaigner_with(name, lock, leaders.clone(), followers.clone())
+        }).collect();
+        let handles: Vec<_> = elections.iter().map(|elector| {
+            let running = elector.clone();
+            let handle = thread::spawn(move || running.run());
+            sleep(Duration::from_millis(50));
+            handle
+        }).collect();
+
+        sleep(Duration::from_millis(200));
+        elections[0].resign_for(Duration::from_millis(1000));
+        assert_eq!(server.holder("http://localhost:8080/locks/failover-lock"), None);
+
+        // The follower takes over without waiting for the TTL, and the
+        // resigned leader doesn't campaign again until it has cooled down.
+        clock.advance(Duration::from_millis(100));
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0", "client1"]);
+
+        elections[1].shutdown();
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0", "client1"]);
+
+        clock.advance(Duration::from_millis(900));
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0", "client1", "client0"]);
+
+        elections[0].shutdown();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+        assert_eq!(server.holder("http://localhost:8080/locks/failover-lock"), None);
+    }
+
+    #[test]
+    fn test_recampaigning_after_losing_leadership() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        let elector = campaigner("client0", &server, leaders.clone(), followers.clone());
+        let running = elector.clone();
+        let handle = thread::spawn(move || running.run());
+
+        sleep(Duration::from_millis(250));
+        // The sidecar expires the lock out from under the leader.
+        server.expire_all();
+        sleep(Duration::from_millis(500));
+
+        elector.shutdown();
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(leaders.lock().unwrap().len(), 2);
+        assert_eq!(followers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_notifying_leadership_transitions() {
+        let clock = ManualClock::new();
+        let server = new_server().clock(clock.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let elections: Vec<_> = vec!["client0", "client1"].into_iter().map(|name| {
+            let (elected, demoted, changed) = (events.clone(), events.clone(), events.clone());
+            election::Election::builder("transition-lock")
+                .lock_builder(Lock::builder("transition-lock")
+                                  .heartbeat_interval(Duration::from_millis(100))
+                                  .holder_id(name)
+                                  .clock(clock.clone())
+                                  .client(server.client(name)))
+                .cancellable_leader(|token| {
+                    while !token.is_cancelled() {
+                        sleep(Duration::from_millis(10));
+                    }
+                })
+                .on_elected(move || elected.lock().unwrap().push(format!("{} elected", name)))
+                .on_demoted(move || demoted.lock().unwrap().push(format!("{} demoted", name)))
+                .on_leader_changed(move |leader| {
+                    let leader = leader.map(|id| id.to_string()).unwrap_or("nobody".to_string());
+                    changed.lock().unwrap().push(format!("{} sees {}", name, leader));
+                })
+                .build()
+                .unwrap()
+        }).collect();
+
+        let handles: Vec<_> = elections.iter().map(|elector| {
+            let running = elector.clone();
+            let handle = thread::spawn(move || running.run());
+            sleep(Duration::from_millis(50));
+            handle
+        }).collect();
+
+        sleep(Duration::from_millis(200));
+        elections[0].shutdown();
+        clock.advance(Duration::from_millis(100));
+        sleep(Duration::from_millis(200));
+        elections[1].shutdown();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+
+        assert_eq!(*events.lock().unwrap(), vec!["client0 elected", "client0 sees client0",
+                                                 "client1 sees client0",
+                                                 "client0 demoted", "client0 sees nobody",
+                                                 "client1 elected", "client1 sees client1",
+                                                 "client1 demoted", "client1 sees nobody"]);
+    }
+
+    #[test]
+    fn test_subscribing_to_leadership_events() {
+        let server = new_server();
+        let elector = election::Election::builder("event-lock")
+                          .lock_builder(Lock::builder("event-lock")
+                                            .heartbeat_interval(Duration::from_millis(100))
+                                            .holder_id("client0")
+                                            .client(server.client("client0")))
+                          .cancellable_leader(|token| {
+                              while !token.is_cancelled() {
+                                  sleep(Duration::from_millis(10));
+                              }
+                          })
+                          .build()
+                          .unwrap();
+        let events = elector.subscribe();
+
+        let running = elector.clone();
+        let handle = thread::spawn(move || running.run());
+        sleep(Duration::from_millis(350));
+        elector.shutdown();
+        assert!(handle.join().unwrap().is_ok());
+
+        let events: Vec<_> = events.try_iter().collect();
+        let renewals = events.iter().filter(|&event| *event == LeadershipEvent::Renewed).count();
+        assert!(renewals >= 2);
+        assert_eq!(events.into_iter().filter(|event| *event != LeadershipEvent::Renewed).collect::<Vec<_>>(),
+                   vec![LeadershipEvent::Campaigning,
+                        LeadershipEvent::Elected,
+                        LeadershipEvent::LeaderObserved(LeaderId::new("client0")),
+                        LeadershipEvent::Demoted,
+                        LeadershipEvent::Stopped]);
+    }
+
+    // Stands in for an observer's client, which must only read locks.
+    #[derive(Debug)]
+    struct ReadOnlyClient(InMemoryLockClient);
+    impl LockClient for ReadOnlyClient {
+        fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError> {
+            self.0.get_lock(lock)
+        }
+
+        fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError> {
+            panic!("observers must not put {}", lock)
+        }
+    }
+
+    #[test]
+    fn test_observing_election() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        let changes = observed.clone();
+        let observer = election::Election::observe("failover-lock")
+                           .lock_builder(Lock::builder("failover-lock")
+                                             .heartbeat_interval(Duration::from_millis(100))
+                                             .client(ReadOnlyClient(server.client("observer"))))
+                           .on_leader_changed(move |leader| changes.lock().unwrap().push(leader))
+                           .build()
+                           .unwrap();
+        let watching = observer.clone();
+        let observing = thread::spawn(move || watching.run());
+
+        let leader = campaigner("client0", &server, leaders.clone(), followers.clone());
+        let running = leader.clone();
+        let leading = thread::spawn(move || running.run());
+
+        sleep(Duration::from_millis(300));
+        assert_eq!(observer.leader().map(|info| info.id().clone()), Some(LeaderId::new("client0")));
+
+        leader.shutdown();
+        assert!(leading.join().unwrap().is_ok());
+        sleep(Duration::from_millis(300));
+
+        observer.shutdown();
+        assert!(observing.join().unwrap().is_ok());
+        assert_eq!(*observed.lock().unwrap(), vec![Some(LeaderId::new("client0")), None]);
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0"]);
+    }
+
+    #[test]
+    fn test_querying_leader() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+
+        let leader = campaigner("client0", &server, leaders.clone(), followers.clone());
+        let follower = campaigner("client1", &server, leaders.clone(), followers.clone());
+        assert_eq!(follower.leader(), None);
+
+        let handles: Vec<_> = vec![leader.clone(), follower.clone()].into_iter().map(|elector| {
+            let handle = thread::spawn(move || elector.run());
+            sleep(Duration::from_millis(50));
+            handle
+        }).collect();
+
+        sleep(Duration::from_millis(250));
+        assert_eq!(follower.leader().map(|info| info.id().clone()), Some(LeaderId::new("client0")));
+        assert_eq!(leader.leader().map(|info| info.id().clone()), Some(LeaderId::new("client0")));
+
+        leader.shutdown();
+        follower.shutdown();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_knowing_leader_named_by_client() {
+        let server = new_server();
+        let leaders = Arc::new(Mutex::new(Vec::new()));
+        let followers = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        // Only the client knows who it is, and no heartbeat asks before the
+        // leader is checked.
+        let lock = Lock::builder("failover-lock")
+                       .heartbeat_interval(Duration::from_secs(10))
+                       .client(server.client("client0"));
+        let mut leader = campaigner_with("client0", lock, leaders.clone(), followers.clone());
+        let changes = observed.clone();
+        leader.on_leader_changed(move |leader| changes.lock().unwrap().push(leader));
+
+        let running = leader.clone();
+        let leading = thread::spawn(move || running.run());
+
+        sleep(Duration::from_millis(200));
+        assert_eq!(*leaders.lock().unwrap(), vec!["client0"]);
+        assert_eq!(leader.leader().map(|info| info.id().clone()), Some(LeaderId::new("client0")));
+        assert_eq!(*observed.lock().unwrap(), vec![Some(LeaderId::new("client0"))]);
+
+        leader.shutdown();
+        assert!(leading.join().unwrap().is_ok());
+    }
+
     #[test]
     fn test_run_election() {
-        let server  = MockLockServer::new();
-        let client0 = MockClient::new("client0", server.clone());
-        let client1 = MockClient::new("client1", server.clone());
-        let client2 = MockClient::new("client2", server.clone());
+        let server  = new_server();
+        let client0 = server.client("client0");
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
 
-        let expected = client0.clone();
+        let expected = client0.holder_id().to_string();
 
-        let leading: Arc<Mutex<Option<MockClient>>> = Arc::new(Mutex::new(None));
-        let mut mutexes: Vec<Arc<Mutex<Option<MockClient>>>> = (0..3).map(|_| leading.clone())
-                                                                     .collect();
+        let leading: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let mut mutexes: Vec<Arc<Mutex<Option<String>>>> = (0..3).map(|_| leading.clone())
+                                                                 .collect();
 
         let clients = vec![client0, client1, client2];
 
@@ -304,7 +1591,7 @@ mod tests {
             let clone = client.clone();
             let elector = elect!("fake-lock", clone,
                                  move || {
-                                     *(mutex.lock()).unwrap() = Some(client.clone());
+                                     *(mutex.lock()).unwrap() = Some(client.holder_id().to_string());
                                      sleep(Duration::from_millis(250));
                                  },
                                  move || {
@@ -0,0 +1,118 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::error;
+use std::fmt;
+
+#[cfg(feature = "async")]
+use hyper;
+use requests::{self, StatusCode};
+
+
+/// Errors returned by the locking and election primitives.
+#[derive(Debug)]
+pub enum SyncError {
+    /// The lock is held by someone else and no retries were requested.
+    Held,
+    /// The lock was still held by someone else after every retry.
+    RetriesExhausted,
+    /// The lock was still held by someone else when the deadline passed.
+    Timeout,
+    /// The lock was lost while the critical section was running.
+    LockLost,
+    /// The lock is already held through this `Lock`, and locks aren't
+    /// reentrant.
+    AlreadyHeld,
+    /// The sidecar could not be reached.
+    Transport(requests::Error),
+    /// The sidecar could not be reached by the async client.
+    #[cfg(feature = "async")]
+    AsyncTransport(hyper::Error),
+    /// The sidecar answered with a status code outside the lock protocol.
+    UnexpectedStatus(StatusCode),
+    /// The lock URI could not be used to reach the sidecar.
+    InvalidUri(String),
+    /// The lock or election was configured with inconsistent settings.
+    InvalidConfig(String),
+}
+
+impl SyncError {
+    /// Whether trying again could succeed, as the sidecar couldn't be
+    /// reached or failed with a server error.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            SyncError::Transport(_) => true,
+            #[cfg(feature = "async")]
+            SyncError::AsyncTransport(_) => true,
+            SyncError::UnexpectedStatus(ref status) => status.to_u16() >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// Alias kept for code written against the original `Lock` error type.
+pub type LockError = SyncError;
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncError::Held => write!(f, "lock is held by another client"),
+            SyncError::RetriesExhausted => write!(f, "lock is still held after retrying"),
+            SyncError::Timeout => write!(f, "timed out waiting for the lock"),
+            SyncError::LockLost => write!(f, "lock was lost while it was held"),
+            SyncError::AlreadyHeld => write!(f, "lock is already held by this holder"),
+            SyncError::Transport(ref err) => write!(f, "could not reach the sidecar: {}", err),
+            #[cfg(feature = "async")]
+            SyncError::AsyncTransport(ref err) => write!(f, "could not reach the sidecar: {}", err),
+            SyncError::UnexpectedStatus(ref status) => write!(f, "unexpected sidecar status: {}", status),
+            SyncError::InvalidUri(ref uri) => write!(f, "invalid lock uri: {}", uri),
+            SyncError::InvalidConfig(ref reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl error::Error for SyncError {
+    fn description(&self) -> &str {
+        match *self {
+            SyncError::Held => "lock is held by another client",
+            SyncError::RetriesExhausted => "lock is still held after retrying",
+            SyncError::Timeout => "timed out waiting for the lock",
+            SyncError::LockLost => "lock was lost while it was held",
+            SyncError::AlreadyHeld => "lock is already held by this holder",
+            SyncError::Transport(_) => "could not reach the sidecar",
+            #[cfg(feature = "async")]
+            SyncError::AsyncTransport(_) => "could not reach the sidecar",
+            SyncError::UnexpectedStatus(_) => "unexpected sidecar status",
+            SyncError::InvalidUri(_) => "invalid lock uri",
+            SyncError::InvalidConfig(_) => "invalid configuration",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SyncError::Transport(ref err) => Some(err),
+            #[cfg(feature = "async")]
+            SyncError::AsyncTransport(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<requests::Error> for SyncError {
+    fn from(err: requests::Error) -> Self {
+        SyncError::Transport(err)
+    }
+}
+
+#[cfg(feature = "async")]
+impl From<hyper::Error> for SyncError {
+    fn from(err: hyper::Error) -> Self {
+        SyncError::AsyncTransport(err)
+    }
+}
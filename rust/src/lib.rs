@@ -18,10 +18,46 @@
 
 #[macro_use]
 extern crate emit;
+extern crate json;
+extern crate rand;
 extern crate requests;
 
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate hyper;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(test)]
+extern crate metaparticle_sync_sidecar;
+
+mod cancel;
+mod client;
+mod clock;
 mod election;
+mod error;
 mod lock;
+mod retry;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
+
+#[cfg(feature = "async")]
+mod async_election;
+#[cfg(feature = "async")]
+mod async_lock;
+
+pub use self::cancel::CancellationToken;
+pub use self::client::{AcquireStatus, LockClient, LockHolder, LockStatus, MockableLockClient, ReleaseStatus};
+pub use self::clock::{Clock, SystemClock};
+pub use self::election::{Election, ElectionBuilder, Handler, LeaderId, LeaderInfo, LeadershipEvent};
+pub use self::error::{LockError, SyncError};
+pub use self::lock::{Lock, LockBuilder, LockEvent, LockGuard, LockState, DEFAULT_BASE_URI, DEFAULT_TTL};
+pub use self::retry::{Jitter, RetryPolicy};
+#[cfg(any(test, feature = "testing"))]
+pub use self::testing::{Fault, FaultyClient, History, HistoryEntry, InMemoryLockClient, InMemoryLockServer, ManualClock,
+                        Overlap};
 
-pub use self::election::{Election, Handler};
-pub use self::lock::{Lock, DEFAULT_BASE_URI};
+#[cfg(feature = "async")]
+pub use self::async_election::AsyncElection;
+#[cfg(feature = "async")]
+pub use self::async_lock::{AsyncLock, AsyncLockClient, LockFuture};
@@ -7,17 +7,26 @@
 // except according to those terms.
 //
 
-use std::fmt::Debug;
-use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread::{sleep, spawn, JoinHandle};
-use std::time::{Duration};
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
 
-use requests::{get, put, Error, StatusCode};
+use cancel::CancellationToken;
+use clock::{self, Clock, SystemClock};
+use client::{AcquireStatus, Client, LockClient, LockHolder, LockStatus, ReleaseStatus};
+use error::SyncError;
+use retry::{as_millis, Backoff, RetryPolicy};
 
 
 pub const DEFAULT_BASE_URI: &'static str = "http://localhost:8080";
 
+/// How long the sidecar holds a lock after each heartbeat when it isn't asked
+/// for a TTL.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
 
 
 /// Helper macro for invoking lock synchronization
@@ -32,90 +41,182 @@ pub const DEFAULT_BASE_URI: &'static str = "http://localhost:8080";
 ///
 /// fn main() {
 ///     let lock = lock!("some-lock");
-///     lock.lock(|| {
+///     if let Err(err) = lock.lock(|| {
 ///         // .. do some work
-///     });
+///     }) {
+///         println!("Could not lock: {:?}", err);
+///     }
 ///
-///     lock!("some-other-lock", || {
+///     if let Err(err) = lock!("some-other-lock", || {
 ///         // .. do some work
-///     });
+///     }) {
+///         println!("Could not lock: {:?}", err);
+///     }
 /// }
 /// ```
 ///
 #[macro_export]
 macro_rules! lock {
-    ($name:tt) => ( $crate::Lock::new($name, $crate::DEFAULT_BASE_URI, 10); );
+    ($name:tt) => ( $crate::Lock::new($name, $crate::DEFAULT_BASE_URI, 10) );
     ($name:tt, $lock_handler:expr) => {{
         let lock = lock!($name);
-        lock.lock( $lock_handler );
+        lock.lock( $lock_handler )
     }}
 }
 
 
-pub trait MockableLockClient: Debug+Send+Sync {
-    fn get_lock(&self, &str) -> Result<StatusCode, Error>;
-    fn put_lock(&self, &str) -> Result<StatusCode, Error>;
+/// Changes in the ownership of a `Lock`, as sent to `Lock::subscribe` receivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockEvent {
+    /// The lock was acquired and is being heartbeated.
+    Acquired,
+    /// A heartbeat renewed the lock on the sidecar.
+    Renewed,
+    /// The lock was released by its holder.
+    Released,
+    /// The heartbeat found the lock no longer belongs to this holder.
+    Lost,
+}
+
+/// The stages a `Lock` moves through, as returned by `Lock::state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    /// Not held, and not trying to acquire it.
+    Idle,
+    /// Asking the sidecar for the lock.
+    Probing,
+    /// Backing off before asking again, as the lock is held elsewhere.
+    Waiting,
+    /// Held and being heartbeated.
+    Holding,
+    /// Released by this holder.
+    Released,
+    /// Lost to another holder, or expired, while it was held.
+    Lost,
+}
+
+// The lock's state, along with a generation counted up from 1 by every
+// acquisition, so that guards and heartbeats left over from an earlier one
+// can't give up a later one.
+struct Transitions(Mutex<(LockState, usize)>);
+
+impl Transitions {
+    fn new() -> Self {
+        Transitions(Mutex::new((LockState::Idle, 0)))
+    }
+
+    fn get(&self) -> LockState {
+        self.0.lock().unwrap().0
+    }
+
+    fn set(&self, state: LockState) {
+        self.0.lock().unwrap().0 = state;
+    }
+
+    fn generation(&self) -> usize {
+        self.0.lock().unwrap().1
+    }
+
+    /// Moves to `Holding`, returning the new acquisition's generation.
+    fn hold(&self) -> usize {
+        let mut current = self.0.lock().unwrap();
+        *current = (LockState::Holding, current.1 + 1);
+        current.1
+    }
+
+    fn is_holding(&self, generation: usize) -> bool {
+        *self.0.lock().unwrap() == (LockState::Holding, generation)
+    }
+
+    /// Moves from `Holding` to `state`, returning whether the lock was held
+    /// by the acquisition `generation`.
+    fn leave(&self, generation: usize, state: LockState) -> bool {
+        let mut current = self.0.lock().unwrap();
+        if *current != (LockState::Holding, generation) {
+            return false
+        }
+        current.0 = state;
+        true
+    }
+}
+
+#[derive(Default)]
+struct Listeners {
+    callbacks: Mutex<Vec<Box<Fn(LockEvent) -> () + Send + Sync>>>,
+    subscribers: Mutex<Vec<Sender<LockEvent>>>,
+}
+
+impl Listeners {
+    fn notify(&self, event: LockEvent) {
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event).is_ok());
+
+        for callback in self.callbacks.lock().unwrap().iter() {
+            callback(event);
+        }
+    }
 }
 
 
 struct Heartbeat{
-    running: AtomicBool,
-    wait_interval: u64,
+    // The generation being heartbeated, or 0 when stopped.
+    running: AtomicUsize,
+    wake: (Mutex<()>, Condvar),
+    // Held across each renewal, so that releasing the lock waits for one in
+    // flight rather than racing it.
+    renewing: Mutex<()>,
+    wait_interval: Duration,
+    clock: Arc<Clock>,
 }
 
 impl Heartbeat {
-    fn new(interval: u64) -> Self {
+    fn new(interval: Duration, clock: Arc<Clock>) -> Self {
         Heartbeat{
-            running: AtomicBool::new(false),
+            running: AtomicUsize::new(0),
+            wake: (Mutex::new(()), Condvar::new()),
+            renewing: Mutex::new(()),
             wait_interval: interval,
+            clock: clock,
         }
     }
 
-    fn is_running(&self) -> bool {
-        self.running.load(Ordering::Relaxed)
+    fn is_running(&self, generation: usize) -> bool {
+        self.running.load(Ordering::Relaxed) == generation
     }
 
-    fn start(&self) {
-        self.running.store(true, Ordering::Relaxed);
+    fn start(&self, generation: usize) {
+        self.running.store(generation, Ordering::Relaxed);
     }
 
-    fn stop(&self) {
-        self.running.store(false, Ordering::Relaxed);
+    /// Stops heartbeating `generation`, waking the heartbeat if it's waiting
+    /// for the next beat. A later generation is left running.
+    fn stop(&self, generation: usize) {
+        let _ = self.running.compare_exchange(generation, 0, Ordering::Relaxed, Ordering::Relaxed);
+        let &(ref mutex, ref condition) = &self.wake;
+        let _guard = mutex.lock().unwrap();
+        condition.notify_all();
     }
 
-    fn beat<F>(&self, mut block: F)
+    fn beat<F>(&self, generation: usize, mut block: F)
     where F: FnMut() -> ()
     {
-        self.start();
-        while self.is_running() {
-            // TODO - Add exponential backoff?
-            sleep(Duration::from_millis(self.wait_interval));
+        while self.wait(generation, self.clock.now() + self.wait_interval) {
             block();
         }
     }
-}
-
-#[derive(Debug)]
-struct Client;
-impl Client {
-    fn new() -> Self {
-        Client{}
-    }
-} 
 
-impl MockableLockClient for Client {
-    fn get_lock(&self, lock: &str) -> Result<StatusCode, Error> {
-        match get(lock) {
-            Ok(response) => Ok(response.status_code()),
-            Err(error)   => Err(error),
-        }
-    }
-
-    fn put_lock(&self, lock: &str) -> Result<StatusCode, Error> {
-        match put(lock) {
-            Ok(response) => Ok(response.status_code()),
-            Err(error)   => Err(error),
+    // Waits for `deadline`, returning whether `generation` is still being
+    // heartbeated.
+    fn wait(&self, generation: usize, deadline: Instant) -> bool {
+        let &(ref mutex, ref condition) = &self.wake;
+        let mut guard = mutex.lock().unwrap();
+        while self.is_running(generation) {
+            let now = self.clock.now();
+            if now >= deadline {
+                return true
+            }
+            guard = self.clock.wait_timeout(condition, guard, deadline - now);
         }
+        false
     }
 }
 
@@ -135,17 +236,20 @@ impl MockableLockClient for Client {
 ///     let lock = lock!("some-held-lock");
 ///
 ///     // Attempt to lock without retrying.
-///     lock.lock(|| {
+///     match lock.lock(|| {
 ///         // do some important work
-///     });
+///     }) {
+///         Ok(()) => println!("Did the important work"),
+///         Err(err) => println!("Could not lock: {:?}", err),
+///     }
 ///
 ///     // Block and attempt to grab the lock up to 10 times.
-///     lock.lock_with_retry(|| {
+///     let _ = lock.lock_with_retry(|| {
 ///         // do some important work
 ///     });
 ///
 ///     // Block and attempt to grab the lock forever.
-///     lock.lock_with_retry_forever(|| {
+///     let _ = lock.lock_with_retry_forever(|| {
 ///         // do some important work
 ///     });
 /// }
@@ -162,17 +266,20 @@ impl MockableLockClient for Client {
 ///     let lock = sync::Lock::new("some-held-lock", sync::DEFAULT_BASE_URI, interval);
 ///
 ///     // Attempt to lock without retrying.
-///     lock.lock(|| {
+///     match lock.lock(|| {
 ///         // do some important work
-///     });
+///     }) {
+///         Ok(()) => println!("Did the important work"),
+///         Err(err) => println!("Could not lock: {:?}", err),
+///     }
 ///
 ///     // Block and attempt to grab the lock up to 10 times.
-///     lock.lock_with_retry(|| {
+///     let _ = lock.lock_with_retry(|| {
 ///         // do some important work
 ///     });
 ///
 ///     // Block and attempt to grab the lock forever.
-///     lock.lock_with_retry_forever(|| {
+///     let _ = lock.lock_with_retry_forever(|| {
 ///         // do some important work
 ///     });
 /// }
@@ -184,72 +291,157 @@ pub struct Lock {
     name: String,
     base_uri: String,
 
-    locked: Arc<AtomicBool>,
-    pub (crate) client: Arc<MockableLockClient>,
+    holder_id: Option<String>,
+    ttl: Option<Duration>,
+    retry_policy: RetryPolicy,
+
+    state: Arc<Transitions>,
+    holder: Arc<Mutex<Option<LockHolder>>>,
+    client: Arc<LockClient>,
+    clock: Arc<Clock>,
     heartbeat: Arc<Heartbeat>,
+    listeners: Arc<Listeners>,
+    token: Arc<Mutex<CancellationToken>>,
 }
 impl Lock {
+    /// Creates a lock heartbeated every `interval` seconds. Use
+    /// `Lock::builder` for finer control.
     pub fn new<S: Into<String>>(name: S, base_uri: S, interval: u64) -> Self {
-        Lock{
-            name: name.into(),
-            base_uri: base_uri.into(),
+        Lock::builder(name)
+            .base_uri(base_uri)
+            .heartbeat_interval(Duration::from_secs(interval))
+            .assemble()
+    }
 
-            client: Arc::new(Client::new()),
-            locked: Arc::new(AtomicBool::new(false)),
-            heartbeat: Arc::new(Heartbeat::new(interval * 1000)),
-        }
+    /// Starts building a lock named `name`.
+    pub fn builder<S: Into<String>>(name: S) -> LockBuilder {
+        LockBuilder::new(name)
     }
 
-    fn uri(&self) -> String {
-        format!("{}/locks/{}", self.base_uri, self.name)
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    fn spin_heartbeat(&self, pair: Arc<(Mutex<bool>, Condvar)>) -> JoinHandle<()> {
-        let uri = self.uri();
-        let client = self.client.clone();
-        let heartbeat = self.heartbeat.clone();
+    pub fn holder_id(&self) -> Option<&str> {
+        self.holder_id.as_ref().map(|holder_id| holder_id.as_str())
+    }
 
-        spawn(move || {
-            heartbeat.beat(|| {
-                let &(ref lock, ref condition) = &*pair;
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat.wait_interval
+    }
 
-                match client.get_lock(&uri) {
-                    Ok(status) => {
-                        if status == StatusCode::Ok {
-                            heartbeat.stop();
-                            let mut available = lock.lock().unwrap();
-                            *available = true;
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
 
-                            condition.notify_one();
-                        }
-                    },
-                    Err(reason) => {
-                        error!("Could not get_lock: {}",
-                               error: reason.to_string())
-                    }
-                }
-            })
-        })
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
     }
 
-    fn hold_heartbeat(&self) -> JoinHandle<()> {
+    pub(crate) fn clock(&self) -> Arc<Clock> {
+        self.clock.clone()
+    }
+
+    fn uri(&self) -> String {
+        format!("{}/locks/{}", self.base_uri, self.name)
+    }
+
+    /// Starts heartbeating a lock whose acquisition was requested at `sent`.
+    fn hold_heartbeat(&self, sent: Instant) -> (usize, JoinHandle<()>) {
         let uri = self.uri();
+        let ttl = self.ttl.unwrap_or(DEFAULT_TTL);
+        let clock = self.clock.clone();
         let client = self.client.clone();
-        let locked = self.locked.clone();
+        let state = self.state.clone();
+        let holder = self.holder.clone();
         let heartbeat = self.heartbeat.clone();
+        let listeners = self.listeners.clone();
+        let token = CancellationToken::new();
+
+        // The sidecar is asked again on the first heartbeat, until then the
+        // holder is known to be us. Clients that name the holder themselves
+        // are asked who that is straight away.
+        let now = self.clock.system_time();
+        let acquired = match self.holder_id {
+            Some(ref id) => {
+                let acquired = LockHolder::new(id.as_str()).acquired_at(now);
+                Some(match self.ttl {
+                    Some(ttl) => acquired.expires_at(now + ttl),
+                    None => acquired,
+                })
+            },
+            None => match client.get_lock(&uri) {
+                Ok(LockStatus::Held(info)) => info,
+                Ok(LockStatus::Free) => None,
+                Err(err) => {
+                    warn!("Could not get holder of lock {}: {}",
+                          lock: uri,
+                          error: err.to_string());
+                    None
+                },
+            },
+        };
+        let holder_id = self.holder_id.clone().or_else(|| {
+            acquired.as_ref().map(|info| info.id().to_string())
+        });
+        *holder.lock().unwrap() = acquired;
+
+        *self.token.lock().unwrap() = token.clone();
+        let generation = state.hold();
+        listeners.notify(LockEvent::Acquired);
+        heartbeat.start(generation);
+        let hold = spawn(move || {
+            let lose = || {
+                heartbeat.stop(generation);
+                token.cancel();
+                if state.leave(generation, LockState::Lost) {
+                    error!("Lost lock {}", lock: uri);
+                    listeners.notify(LockEvent::Lost);
+                }
+            };
 
-        locked.store(true, Ordering::Relaxed);
-        spawn(move || {
-            heartbeat.beat(|| {
+            let interval = heartbeat.wait_interval;
+            let mut renewed = sent;
+            heartbeat.beat(generation, || {
+                let sent = clock.now();
                 match client.get_lock(&uri) {
-                    Ok(status) => {
-                        if status == StatusCode::Ok {
-                            // Do we need to check the result here is ok?
-                            let _ = client.put_lock(&uri);
-                        } else {
-                            heartbeat.stop();
-                            locked.store(false, Ordering::Relaxed);
+                    Ok(LockStatus::Held(info)) => {
+                        // Someone else holding the lock means it's theirs now.
+                        let taken = match (info.as_ref(), holder_id.as_ref()) {
+                            (Some(info), Some(id)) => info.id() != id,
+                            _ => false,
+                        };
+                        *holder.lock().unwrap() = info;
+                        if taken {
+                            return lose()
                         }
+
+                        // Renewing after `unlock` would take the lock back.
+                        let renewal = {
+                            let _renewing = heartbeat.renewing.lock().unwrap();
+                            if !heartbeat.is_running(generation) {
+                                return
+                            }
+                            client.put_lock(&uri)
+                        };
+
+                        // Someone else renewing the lock means it's theirs now.
+                        match renewal {
+                            Ok(AcquireStatus::Acquired) => {
+                                renewed = sent;
+                                listeners.notify(LockEvent::Renewed)
+                            },
+                            Ok(AcquireStatus::Conflict) => return lose(),
+                            Err(err) => {
+                                warn!("Could not renew lock {}: {}",
+                                      lock: uri,
+                                      error: err.to_string())
+                            },
+                        }
+                    },
+                    Ok(LockStatus::Free) => {
+                        *holder.lock().unwrap() = None;
+                        return lose()
                     },
                     Err(err) => {
                         error!("Could not get lock {}: {}",
@@ -257,225 +449,1227 @@ impl Lock {
                                error: err.to_string())
                     }
                 }
+
+                // Give the lock up before the sidecar expires it, when the
+                // next heartbeat could be too late to renew it.
+                if clock.now() + interval >= renewed + ttl {
+                    error!("Could not renew lock {} within its TTL", lock: uri);
+                    lose()
+                }
             })
-        })
+        });
+        (generation, hold)
+    }
+
+    /// The holder of the lock when the sidecar was last asked, if it
+    /// reports one. It's refreshed by every heartbeat and acquisition attempt.
+    pub fn holder(&self) -> Option<LockHolder> {
+        self.holder.lock().unwrap().clone()
     }
 
     pub fn is_locked(&self) -> bool {
-        self.locked.load(Ordering::Relaxed)
+        self.state() == LockState::Holding
+    }
+
+    /// Where the lock is in acquiring, holding and giving it up.
+    pub fn state(&self) -> LockState {
+        self.state.get()
+    }
+
+    /// Registers a callback invoked from the heartbeat thread when the lock
+    /// is lost while it's held, so that work in progress can abort.
+    pub fn on_lock_lost<F>(&self, callback: F)
+    where F: Fn() -> () + Send + Sync + 'static
+    {
+        self.on_event(move |event| {
+            if event == LockEvent::Lost {
+                callback()
+            }
+        });
+    }
+
+    /// Registers a callback invoked with every change in the lock's
+    /// ownership, from whichever thread makes it.
+    pub(crate) fn on_event<F>(&self, callback: F)
+    where F: Fn(LockEvent) -> () + Send + Sync + 'static
+    {
+        self.listeners.callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    /// Returns a receiver for every subsequent change in the lock's ownership.
+    pub fn subscribe(&self) -> Receiver<LockEvent> {
+        let (sender, receiver) = channel();
+        self.listeners.subscribers.lock().unwrap().push(sender);
+        receiver
     }
 
+    /// Stops heartbeating, cancels the holder's token and releases the lock
+    /// on the sidecar.
     pub fn unlock(&self) {
-        self.heartbeat.stop();
-        self.locked.store(false, Ordering::Relaxed);
+        let generation = self.state.generation();
+        self.heartbeat.stop(generation);
+        self.token.lock().unwrap().cancel();
+
+        let _renewing = self.heartbeat.renewing.lock().unwrap();
+        if self.state.leave(generation, LockState::Released) {
+            self.release();
+        }
+    }
+
+    fn release(&self) {
+        self.listeners.notify(LockEvent::Released);
+        match self.client.delete_lock(&self.uri()) {
+            Ok(ReleaseStatus::Released) => {},
+            Ok(ReleaseStatus::Unsupported) => {
+                info!("Lock {} will expire with its TTL", lock: self.name)
+            },
+            Ok(ReleaseStatus::NotHeld) => {
+                error!("Could not release lock {}: it is held elsewhere",
+                       lock: self.uri())
+            },
+            Err(err) => {
+                error!("Could not release lock {}: {}",
+                       lock: self.uri(),
+                       error: err.to_string())
+            },
+        }
+    }
+
+    /// Acquires the lock, waiting until it's available, and returns a guard
+    /// that holds it until dropped.
+    pub fn acquire(&self) -> Result<LockGuard, SyncError> {
+        self.acquire_with_policy(RetryPolicy::forever())
+    }
+
+    /// Acquires the lock without retrying if it's held elsewhere.
+    pub fn try_acquire(&self) -> Result<LockGuard, SyncError> {
+        self.acquire_with_policy(RetryPolicy::no_retry())
+    }
+
+    /// Acquires the lock, retrying as `policy` allows while it's held
+    /// elsewhere.
+    pub fn acquire_with_policy(&self, policy: RetryPolicy) -> Result<LockGuard, SyncError> {
+        self._acquire(&mut policy.backoff(self.clock.clone()), None)
+    }
+
+    /// Acquires the lock, waiting at most `timeout` for it to become
+    /// available before failing with `SyncError::Timeout`.
+    pub fn try_acquire_for(&self, timeout: Duration) -> Result<LockGuard, SyncError> {
+        let clock = self.clock.clone();
+        let deadline = clock.now() + timeout;
+        self._acquire(&mut RetryPolicy::forever().backoff(clock), Some(deadline))
     }
 
-    pub fn lock<T: Fn() -> ()>(&self, func: T){
-        self._lock(0, func);
+    /// Runs `func` while holding the lock, retrying as the lock's retry
+    /// policy allows (by default, not at all) if it's held elsewhere.
+    /// Returns the closure's result on success.
+    pub fn lock<T, R>(&self, func: T) -> Result<R, SyncError>
+    where T: FnOnce() -> R
+    {
+        self._lock(self.retry_policy.clone(), |_| func())
     }
 
-    pub fn lock_with_retry<T: Fn() -> ()>(&self, func: T){
-        self._lock(10, func); // TODO - Should this be specified?
+    /// Like `lock`, but passes `func` a token that's cancelled if the lock
+    /// is lost or unlocked while `func` runs.
+    pub fn lock_cancellable<T, R>(&self, func: T) -> Result<R, SyncError>
+    where T: FnOnce(CancellationToken) -> R
+    {
+        self._lock(self.retry_policy.clone(), func)
     }
 
-    pub fn lock_with_retry_forever<T: Fn() -> ()>(&self, func: T) {
-        self._lock(-1, func);
+    /// Like `lock_cancellable`, but retries as `policy` allows while the lock
+    /// is held elsewhere.
+    pub(crate) fn lock_cancellable_with_policy<T, R>(&self, policy: RetryPolicy, func: T) -> Result<R, SyncError>
+    where T: FnOnce(CancellationToken) -> R
+    {
+        self._lock(policy, func)
     }
 
-    fn _lock<T: Fn() -> ()>(&self, retry: i8, func: T) {
+    /// Like `lock`, but waits for the lock and retries up to 10 times.
+    pub fn lock_with_retry<T, R>(&self, func: T) -> Result<R, SyncError>
+    where T: FnOnce() -> R
+    {
+        self._lock(RetryPolicy::retries(10), |_| func())
+    }
+
+    /// Like `lock`, but waits for the lock until it's acquired.
+    pub fn lock_with_retry_forever<T, R>(&self, func: T) -> Result<R, SyncError>
+    where T: FnOnce() -> R
+    {
+        self._lock(RetryPolicy::forever(), |_| func())
+    }
+
+    /// Like `lock`, but retries as `policy` allows while the lock is held
+    /// elsewhere.
+    pub fn lock_with_policy<T, R>(&self, policy: RetryPolicy, func: T) -> Result<R, SyncError>
+    where T: FnOnce() -> R
+    {
+        self._lock(policy, |_| func())
+    }
+
+    /// Like `lock`, but waits at most `timeout` for the lock before failing
+    /// with `SyncError::Timeout`.
+    ///
+    /// # Example
+    /// ```
+    /// extern crate metaparticle_sync as sync;
+    ///
+    /// use std::time::Duration;
+    ///
+    /// fn main() {
+    ///     let lock = sync::Lock::new("some-held-lock", sync::DEFAULT_BASE_URI, 10);
+    ///
+    ///     match lock.lock_with_timeout(Duration::from_secs(45), || {
+    ///         // do some important work
+    ///     }) {
+    ///         Err(sync::SyncError::Timeout) => println!("Gave up waiting for the lock"),
+    ///         _ => {},
+    ///     }
+    /// }
+    /// ```
+    pub fn lock_with_timeout<T, R>(&self, timeout: Duration, func: T) -> Result<R, SyncError>
+    where T: FnOnce() -> R
+    {
+        let guard = self.try_acquire_for(timeout)?;
+        self._hold(guard, |_| func())
+    }
+
+    fn _lock<T, R>(&self, policy: RetryPolicy, func: T) -> Result<R, SyncError>
+    where T: FnOnce(CancellationToken) -> R
+    {
+        let guard = self.acquire_with_policy(policy)?;
+        self._hold(guard, func)
+    }
+
+    fn _hold<T, R>(&self, guard: LockGuard, func: T) -> Result<R, SyncError>
+    where T: FnOnce(CancellationToken) -> R
+    {
+
+        let result = func(guard.token());
+        let lost = !guard.is_held();
+        drop(guard);
+
+        if lost {
+            error!("Lost lock {} while it was held", lock: self.name);
+            return Err(SyncError::LockLost)
+        }
+        Ok(result)
+    }
+
+    fn _acquire(&self, backoff: &mut Backoff, deadline: Option<Instant>) -> Result<LockGuard, SyncError> {
         if self.is_locked() {
             error!("Locks are not reentrant {}", lock: self.name);
+            return Err(SyncError::AlreadyHeld)
         }
 
-        match self.client.get_lock(&self.uri()) {
-            Ok(status) => {
-                match status {
-                    StatusCode::Ok | StatusCode::NotFound => {
-                        match self.client.put_lock(&self.uri()) {
-                            Ok(status) => {
-                                match status {
-                                    StatusCode::Ok => {
-                                        let hold = self.hold_heartbeat();
-
-                                        func();
-
-                                        self.heartbeat.stop();
-                                        let _ = hold.join(); // The handle output is unimportant
-                                    },
-                                    StatusCode::Conflict => {
-                                        if retry == 0 {
-                                            info!("Couldn't grab lock {} retry {}", lock: self.name, retry: retry);
-                                            return
-                                        }
-
-                                        let pair = Arc::new((Mutex::new(false), Condvar::new()));
-                                        let spin = self.spin_heartbeat(pair.clone());
-
-                                        let &(ref lock, ref condition) = &*pair;
-                                        let mut available = lock.lock().unwrap();
-                                        loop {
-                                            let r = condition.wait_timeout(available, Duration::from_millis(500))
-                                                             .unwrap();
-
-                                            available = r.0;
-                                            if *available {
-                                                break
-                                            }
-                                        }
-                                        let _ = spin.join();
-
-                                        let mut retry = retry;
-                                        if retry != -1 {
-                                            retry -= 1;
-                                        }
-
-                                        self._lock(retry, func);
-                                    },
-                                    _ => unreachable!(),
-                                }
-                            },
-                            Err(err) => {
-                                error!("Could not put lock {}: {}",
-                                       lock: self.uri(),
-                                       error: err.to_string())
-                            }
-                        }
-                    },
-                    _ => {
-                        unreachable!();
-                    },
-                }
-            },
+        loop {
+            self.state.set(LockState::Probing);
+            let sent = self.clock.now();
+            let delay = match self.probe() {
+                Ok(true) => {
+                    let (generation, hold) = self.hold_heartbeat(sent);
+                    return Ok(LockGuard{
+                        generation: generation,
+                        hold: Some(hold),
+                        token: self.token.lock().unwrap().clone(),
+                        lock: self.clone(),
+                    })
+                },
+                Ok(false) => self.next_wait(backoff, deadline),
+                Err(err) => Err(err),
+            };
+
+            match delay {
+                Ok(delay) => {
+                    self.state.set(LockState::Waiting);
+                    let clock = &*self.clock;
+                    clock::sleep_until(clock, clock.now() + delay, || false);
+                },
+                Err(err) => {
+                    self.state.set(LockState::Idle);
+                    return Err(err)
+                },
+            }
+        }
+    }
+
+    /// Asks the sidecar for the lock, returning whether it was acquired.
+    fn probe(&self) -> Result<bool, SyncError> {
+        self.watch()?;
+
+        let status = match self.client.put_lock(&self.uri()) {
+            Ok(status) => status,
+            Err(err) => {
+                error!("Could not put lock {}: {}",
+                       lock: self.uri(),
+                       error: err.to_string());
+                return Err(err)
+            }
+        };
+
+        Ok(status == AcquireStatus::Acquired)
+    }
+
+    /// Asks the sidecar who holds the lock, without ever trying to take it,
+    /// and refreshes `holder` with the answer.
+    pub(crate) fn watch(&self) -> Result<LockStatus, SyncError> {
+        let status = match self.client.get_lock(&self.uri()) {
+            Ok(status) => status,
             Err(err) => {
                 error!("Could not get lock {}: {}",
                        lock: self.uri(),
-                       error: err.to_string())
+                       error: err.to_string());
+                return Err(err)
+            }
+        };
+
+        *self.holder.lock().unwrap() = match status {
+            LockStatus::Held(ref info) => info.clone(),
+            LockStatus::Free => None,
+        };
+        Ok(status)
+    }
+
+    /// How long to wait before probing again after a conflict, or why to
+    /// give up instead.
+    fn next_wait(&self, backoff: &mut Backoff, deadline: Option<Instant>) -> Result<Duration, SyncError> {
+        let delay = match backoff.next_delay() {
+            Some(delay) => delay,
+            None if backoff.attempts() == 1 => return Err(SyncError::Held),
+            None => return Err(SyncError::RetriesExhausted),
+        };
+        info!("Couldn't grab lock {} attempt {}",
+              lock: self.name,
+              attempt: backoff.attempts());
+
+        // Never sleep past the deadline, but make one last attempt when it's
+        // reached.
+        match deadline {
+            Some(deadline) => {
+                let now = self.clock.now();
+                if now >= deadline {
+                    info!("Timed out waiting for lock {}", lock: self.name);
+                    return Err(SyncError::Timeout)
+                }
+                Ok(cmp::min(delay, deadline - now))
+            },
+            None => Ok(delay),
+        }
+    }
+}
+
+
+/// Builds a `Lock`.
+///
+/// The heartbeat renews the lock every `heartbeat_interval`, so it has to be
+/// well inside the `ttl` the sidecar is asked to hold the lock for; `build`
+/// rejects intervals longer than half the TTL.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let lock = sync::Lock::builder("some-held-lock")
+///                    .base_uri("http://localhost:8080")
+///                    .heartbeat_interval(Duration::from_secs(5))
+///                    .ttl(Duration::from_secs(15))
+///                    .holder_id("replica-1")
+///                    .retry_policy(sync::RetryPolicy::retries(3))
+///                    .build()
+///                    .expect("a valid lock configuration");
+///
+///     let _ = lock.lock(|| {
+///         // do some important work
+///     });
+/// }
+/// ```
+pub struct LockBuilder {
+    name: String,
+    base_uri: String,
+    heartbeat_interval: Duration,
+    ttl: Option<Duration>,
+    holder_id: Option<String>,
+    client: Option<Arc<LockClient>>,
+    clock: Arc<Clock>,
+    retry_policy: RetryPolicy,
+}
+
+impl LockBuilder {
+    fn new<S: Into<String>>(name: S) -> Self {
+        LockBuilder{
+            name: name.into(),
+            base_uri: DEFAULT_BASE_URI.to_string(),
+            heartbeat_interval: Duration::from_secs(10),
+            ttl: None,
+            holder_id: None,
+            client: None,
+            clock: Arc::new(SystemClock),
+            retry_policy: RetryPolicy::no_retry(),
+        }
+    }
+
+    pub fn base_uri<S: Into<String>>(mut self, base_uri: S) -> Self {
+        self.base_uri = base_uri.into();
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// How long the sidecar should hold the lock after each heartbeat. The
+    /// lock is given up as lost once heartbeats have failed to renew it for
+    /// close to this long, or to `DEFAULT_TTL` when the sidecar isn't asked
+    /// for one.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Identifies this holder to the sidecar.
+    pub fn holder_id<S: Into<String>>(mut self, holder_id: S) -> Self {
+        self.holder_id = Some(holder_id.into());
+        self
+    }
+
+    /// Talks to the sidecar through `client` rather than over HTTP.
+    pub fn client<C: LockClient + 'static>(self, client: C) -> Self {
+        self.shared_client(Arc::new(client))
+    }
+
+    /// Like `client`, for a client shared with other locks.
+    pub fn shared_client(mut self, client: Arc<LockClient>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Heartbeats and retries by `clock` rather than the system's.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The policy `lock` and `lock_cancellable` retry with.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<Lock, SyncError> {
+        if self.heartbeat_interval == Duration::from_secs(0) {
+            return Err(SyncError::InvalidConfig("heartbeat interval must be positive".to_string()))
+        }
+        if let Some(ttl) = self.ttl {
+            if self.heartbeat_interval * 2 > ttl {
+                return Err(SyncError::InvalidConfig(
+                    format!("heartbeat interval of {}ms must be at most half the {}ms ttl",
+                            as_millis(self.heartbeat_interval), as_millis(ttl))))
             }
         }
+        if self.client.is_none() {
+            Client::check_uri(&self.base_uri)?;
+        }
+        Ok(self.assemble())
+    }
+
+    fn assemble(self) -> Lock {
+        let client = match self.client {
+            Some(client) => client,
+            None => Arc::new(Client{
+                holder_id: self.holder_id.clone(),
+                ttl: self.ttl,
+            }),
+        };
+
+        Lock{
+            name: self.name,
+            base_uri: self.base_uri,
+
+            holder_id: self.holder_id,
+            ttl: self.ttl,
+            retry_policy: self.retry_policy,
+
+            client: client,
+            clock: self.clock.clone(),
+            state: Arc::new(Transitions::new()),
+            holder: Arc::new(Mutex::new(None)),
+            heartbeat: Arc::new(Heartbeat::new(self.heartbeat_interval, self.clock)),
+            listeners: Arc::new(Listeners::default()),
+            token: Arc::new(Mutex::new(CancellationToken::new())),
+        }
+    }
+}
+
+
+/// Holds a `Lock` until dropped.
+///
+/// The guard keeps the lock's heartbeat running, so the lock stays held for
+/// as long as the guard is alive, across `?` early returns and function
+/// boundaries. Dropping the guard stops the heartbeat and releases the lock
+/// on the sidecar.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// fn migrate(lock: &sync::Lock) -> Result<(), sync::SyncError> {
+///     let guard = lock.try_acquire()?;
+///
+///     // .. do some work, checking that the lock is still ours
+///     if !guard.is_held() {
+///         return Err(sync::SyncError::LockLost)
+///     }
+///
+///     Ok(())
+/// }
+///
+/// fn main() {
+///     let lock = sync::Lock::new("some-held-lock", sync::DEFAULT_BASE_URI, 10);
+///     if let Err(err) = migrate(&lock) {
+///         println!("Could not migrate: {}", err);
+///     }
+/// }
+/// ```
+pub struct LockGuard {
+    lock: Lock,
+    generation: usize,
+    hold: Option<JoinHandle<()>>,
+    token: CancellationToken,
+}
+
+impl LockGuard {
+    /// Whether the lock is still held, i.e. the heartbeat hasn't lost it.
+    pub fn is_held(&self) -> bool {
+        self.lock.state.is_holding(self.generation)
+    }
+
+    /// A token that's cancelled once the lock is lost, unlocked or released.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for LockGuard {
+    // Only this guard's acquisition is given up, not one made since it was
+    // unlocked, through this lock or a clone of it.
+    fn drop(&mut self) {
+        self.lock.heartbeat.stop(self.generation);
+        self.token.cancel();
+        if let Some(hold) = self.hold.take() {
+            let _ = hold.join(); // The handle output is unimportant
+        }
+        if self.lock.state.leave(self.generation, LockState::Released) {
+            self.lock.release();
+        }
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    extern crate requests;
-
-    use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
-    use std::thread::sleep;
+    use std::thread::{sleep, spawn};
     use std::time::{Duration, Instant};
 
-    use requests::{StatusCode, Error};
-
-    use lock::{Lock, MockableLockClient};
+    use metaparticle_sync_sidecar::Sidecar;
+    use rand::{thread_rng, Rng};
+    use requests::StatusCode;
+
+    use client::{AcquireStatus, LockClient, LockStatus, MockableLockClient};
+    use error::SyncError;
+    use lock::{Lock, LockEvent, LockState, DEFAULT_TTL};
+    use retry::{Jitter, RetryPolicy};
+    use testing::{Fault, FaultyClient, History, InMemoryLockClient, InMemoryLockServer, ManualClock};
+
+    fn new_lock(client: &InMemoryLockClient, lock: &str) -> Lock {
+        Lock::builder(lock)
+            .heartbeat_interval(Duration::from_millis(100))
+            .client(client.clone())
+            .build()
+            .unwrap()
+    }
 
-    #[derive(Debug,Clone)]
-    struct MockLock((String, Instant));
+    fn new_server() -> InMemoryLockServer {
+        InMemoryLockServer::with_ttl(Duration::from_secs(1))
+    }
 
-    #[derive(Debug,Clone)]
-    struct MockLockServer(Arc<Mutex<HashMap<String, MockLock>>>);
-    impl MockLockServer {
-        fn new() -> Self {
-            MockLockServer(Arc::new(Mutex::new(HashMap::new())))
+    // Advances `clock` by `step` at most `steps` times, giving heartbeats a
+    // moment to catch up each time, until `done`.
+    fn advance_until<F: Fn() -> bool>(clock: &ManualClock, step: Duration, steps: u32, done: F) -> bool {
+        for _ in 0..steps {
+            clock.advance(step);
+            sleep(Duration::from_millis(50));
+            if done() {
+                return true
+            }
         }
+        false
     }
 
-    #[derive(Debug,Clone)]
-    struct MockClient((String, MockLockServer));
-    impl MockClient {
-        fn new<T: Into<String>>(client: T, server: MockLockServer) -> Self {
-            MockClient((client.into(), server))
+    // Stands in for a sidecar that doesn't support DELETE.
+    #[derive(Debug)]
+    struct NoDeleteClient(InMemoryLockClient);
+    impl LockClient for NoDeleteClient {
+        fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError> {
+            self.0.get_lock(lock)
         }
 
-        fn new_lock<T: Into<String>>(&self, lock: T, base_uri: T) -> Lock {
-            let mut lock = Lock::new(lock, base_uri, 1);
-            let client = Arc::new(self.clone());
-            lock.client = client;
-            lock
+        fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError> {
+            self.0.put_lock(lock)
         }
     }
 
-    impl MockableLockClient for MockClient {
-        fn get_lock(&self, lock: &str) -> Result<StatusCode, Error> {
-            let &MockClient((_, ref mutex)) = self;
-            let locks = mutex.0.lock().unwrap();
+    #[derive(Debug)]
+    struct UnavailableClient;
+    impl MockableLockClient for UnavailableClient {
+        fn get_lock(&self, _: &str) -> Result<StatusCode, SyncError> {
+            Ok(StatusCode::ServiceUnavailable)
+        }
 
-            if locks.contains_key(lock) {
-                return Ok(StatusCode::Ok);
-            }
-            Ok(StatusCode::NotFound)
+        fn put_lock(&self, _: &str) -> Result<StatusCode, SyncError> {
+            Ok(StatusCode::ServiceUnavailable)
         }
+    }
 
-        fn put_lock(&self, lock: &str) -> Result<StatusCode, Error> {
-            let &MockClient((ref client, ref mutex)) = self;
-            let mut locks = mutex.0.lock().unwrap();
+    #[test]
+    fn test_locking_with_unexpected_status() {
+        let lock = Lock::builder("unavailable")
+                       .client(UnavailableClient)
+                       .build()
+                       .unwrap();
+
+        match lock.lock(|| {}) {
+            Err(SyncError::UnexpectedStatus(StatusCode::ServiceUnavailable)) => {},
+            other => panic!("expected an unexpected status error, got {:?}", other),
+        }
+    }
 
-            match locks.get(lock) {
-                Some(mocklock) => {
-                    let &MockLock((ref lockclient, ref timeout)) = mocklock;
-                    let elapsed = Instant::now().duration_since(*timeout);
+    #[test]
+    fn test_building_locks() {
+        let lock = Lock::builder("built")
+                       .base_uri("http://localhost:9090")
+                       .heartbeat_interval(Duration::from_millis(500))
+                       .ttl(Duration::from_secs(2))
+                       .holder_id("replica-1")
+                       .build()
+                       .unwrap();
+
+        assert_eq!(lock.name(), "built");
+        assert_eq!(lock.holder_id(), Some("replica-1"));
+        assert_eq!(lock.heartbeat_interval(), Duration::from_millis(500));
+        assert_eq!(lock.ttl(), Some(Duration::from_secs(2)));
+
+        match Lock::builder("built").heartbeat_interval(Duration::from_secs(5))
+                                    .ttl(Duration::from_secs(8))
+                                    .build() {
+            Err(SyncError::InvalidConfig(_)) => {},
+            other => panic!("expected SyncError::InvalidConfig, got {:?}", other.map(|_| ())),
+        }
 
-                    if lockclient != &*client && elapsed < Duration::new(1, 0) {
-                        return Ok(StatusCode::Conflict)
-                    }
-                },
-                _ => {},
-            }
-            locks.insert(lock.to_string(), MockLock((client.to_string(), Instant::now())));
-            Ok(StatusCode::Ok)
+        match Lock::builder("built").base_uri("localhost:8080").build() {
+            Err(SyncError::InvalidUri(_)) => {},
+            other => panic!("expected SyncError::InvalidUri, got {:?}", other.map(|_| ())),
         }
     }
 
     #[test]
     fn test_locking_with_macros() {
-        lock!("macro-lock", || { sleep(Duration::from_millis(250)) })
+        let _ = lock!("macro-lock", || { sleep(Duration::from_millis(250)) });
     }
 
     #[test]
     fn test_locking_without_retrying() {
-        let server = MockLockServer::new();
-        let client1 = MockClient::new("client1", server.clone());
-        let client2 = MockClient::new("client2", server.clone());
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
         
-        let lock  = client1.new_lock("good", "localhost:8080");
-        let lock2 = client2.new_lock("good", "localhost:8080");
+        let lock  = new_lock(&client1, "good");
+        let lock2 = new_lock(&client2, "good");
 
-        lock.lock(|| {
-            println!("DOING THE WORK");
-            sleep(Duration::from_millis(250));
-            println!("DONE!");
-        });
-        lock2.lock(|| {
+        let result = lock.lock(|| {
             println!("DOING THE WORK");
+            let result2 = lock2.lock(|| {
+                println!("DOING THE WORK");
+                sleep(Duration::from_millis(250));
+                println!("DONE!");
+                "work"
+            });
             sleep(Duration::from_millis(250));
             println!("DONE!");
+            ("work", result2)
         });
+        let (result, result2) = result.unwrap();
 
-        assert_eq!(lock.is_locked(), true);
+        assert_eq!(result, "work");
+        match result2 {
+            Err(SyncError::Held) => {},
+            other => panic!("expected SyncError::Held, got {:?}", other),
+        }
+        assert_eq!(lock.is_locked(), false);
         assert_eq!(lock2.is_locked(), false);
     }
 
     #[test]
     fn test_locking_with_retrying() {
-        let server = MockLockServer::new();
-        let client1 = MockClient::new("client1", server.clone());
-        let client2 = MockClient::new("client2", server.clone());
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
         
-        let lock  = client1.new_lock("good", "localhost:8080");
-        let lock2 = client2.new_lock("good", "localhost:8080");
+        let lock  = new_lock(&client1, "good");
+        let lock2 = new_lock(&client2, "good");
 
-        lock.lock_with_retry(|| {
+        let result = lock.lock_with_retry(|| {
             println!("DOING THE WORK");
             sleep(Duration::from_millis(250));
             println!("DONE!");
         });
-        lock2.lock_with_retry(|| {
+        let result2 = lock2.lock_with_retry(|| {
             println!("DOING THE WORK");
             sleep(Duration::from_millis(250));
             println!("DONE!");
         });
 
-        assert_eq!(lock.is_locked(), true);
-        assert_eq!(lock2.is_locked(), true);
+        assert!(result.is_ok());
+        assert!(result2.is_ok());
+        assert_eq!(lock.is_locked(), false);
+        assert_eq!(lock2.is_locked(), false);
+    }
+
+    #[test]
+    fn test_locking_with_policy() {
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock  = new_lock(&client1, "policy");
+        let lock2 = new_lock(&client2, "policy");
+
+        let policy = RetryPolicy::forever()
+                         .max_attempts(3)
+                         .initial_delay(Duration::from_millis(50))
+                         .jitter(Jitter::None);
+
+        let started = Instant::now();
+        let result = lock.lock(|| lock2.lock_with_policy(policy.clone(), || {}));
+        match result {
+            Ok(Err(SyncError::RetriesExhausted)) => {},
+            other => panic!("expected SyncError::RetriesExhausted, got {:?}", other),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        assert!(lock2.lock_with_policy(policy, || {}).is_ok());
+    }
+
+    #[test]
+    fn test_locking_with_timeout() {
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock  = new_lock(&client1, "timeout");
+        let lock2 = new_lock(&client2, "timeout");
+
+        let result = lock.lock(|| {
+            let started = Instant::now();
+            (lock2.lock_with_timeout(Duration::from_millis(300), || {}), started.elapsed())
+        });
+        let (result, elapsed) = result.unwrap();
+        match result {
+            Err(SyncError::Timeout) => {},
+            other => panic!("expected SyncError::Timeout, got {:?}", other),
+        }
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_secs(1));
+
+        assert!(lock2.try_acquire_for(Duration::from_millis(300)).is_ok());
+    }
+
+    #[test]
+    fn test_locking_with_owned_and_mutable_state() {
+        let server = new_server();
+        let client = server.client("client1");
+        let lock = new_lock(&client, "stateful");
+
+        let owned = vec![1, 2, 3];
+        let mut counter = 0;
+
+        let total = lock.lock(|| {
+            counter += 1;
+            owned.into_iter().sum::<i32>()
+        });
+
+        assert_eq!(total.unwrap(), 6);
+        assert_eq!(counter, 1);
+    }
+
+    #[test]
+    fn test_releasing_on_unlock() {
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock  = new_lock(&client1, "released");
+        let lock2 = new_lock(&client2, "released");
+
+        assert!(lock.lock(|| {}).is_ok());
+        assert!(lock2.lock(|| {}).is_ok());
+
+        let guard = lock.try_acquire().unwrap();
+        lock.unlock();
+        assert_eq!(guard.is_held(), false);
+        assert!(lock2.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_releasing_during_renewal() {
+        let server = new_server();
+        let client = FaultyClient::new(server.client("client1"));
+        let lock = Lock::builder("renewed")
+                       .heartbeat_interval(Duration::from_millis(100))
+                       .client(client.clone())
+                       .build()
+                       .unwrap();
+
+        // The first heartbeat's PUT is still in flight when the lock is
+        // unlocked, and mustn't land after the lock's been released.
+        client.script(vec![Fault::Pass, Fault::Pass, Fault::Pass, Fault::Delay(Duration::from_millis(200))]);
+        let guard = lock.try_acquire().unwrap();
+        sleep(Duration::from_millis(200));
+        lock.unlock();
+
+        sleep(Duration::from_millis(300));
+        assert_eq!(server.holder("http://localhost:8080/locks/renewed"), None);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_releasing_without_sidecar_support() {
+        let clock = ManualClock::new();
+        let server = new_server().clock(clock.clone());
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock = Lock::builder("expiring")
+                       .heartbeat_interval(Duration::from_millis(100))
+                       .clock(clock.clone())
+                       .client(NoDeleteClient(client1))
+                       .build()
+                       .unwrap();
+        let lock2 = new_lock(&client2, "expiring");
+
+        assert!(lock.lock(|| {}).is_ok());
+        match lock2.lock(|| {}) {
+            Err(SyncError::Held) => {},
+            other => panic!("expected SyncError::Held, got {:?}", other),
+        }
+
+        clock.advance(server.ttl());
+        assert!(lock2.lock(|| {}).is_ok());
+    }
+
+    #[test]
+    fn test_retrying_by_clock() {
+        let clock = ManualClock::new();
+        let server = new_server().clock(clock.clone());
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock = new_lock(&client1, "clocked");
+        let lock2 = Lock::builder("clocked")
+                        .clock(clock.clone())
+                        .client(client2)
+                        .build()
+                        .unwrap();
+
+        let policy = RetryPolicy::forever()
+                         .initial_delay(Duration::from_secs(5))
+                         .jitter(Jitter::None);
+
+        let guard = lock.try_acquire().unwrap();
+        let waiter = lock2.clone();
+        let waiting = spawn(move || waiter.lock_with_policy(policy, || {}));
+        while lock2.state() != LockState::Waiting {
+            sleep(Duration::from_millis(10));
+        }
+        drop(guard);
+
+        // Nothing is retried until the clock says the backoff has passed.
+        sleep(Duration::from_millis(100));
+        assert_eq!(lock2.state(), LockState::Waiting);
+        clock.advance(Duration::from_secs(5));
+        assert!(waiting.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_notifying_lost_lock() {
+        let server = new_server();
+        let client = server.client("client1");
+        let lock = new_lock(&client, "lost");
+
+        let lost = Arc::new(Mutex::new(0));
+        let counter = lost.clone();
+        lock.on_lock_lost(move || *counter.lock().unwrap() += 1);
+        let events = lock.subscribe();
+
+        let result = lock.lock(|| {
+            // The sidecar expires the lock out from under us.
+            server.expire_all();
+            sleep(Duration::from_millis(1500));
+        });
+
+        match result {
+            Err(SyncError::LockLost) => {},
+            other => panic!("expected SyncError::LockLost, got {:?}", other),
+        }
+        assert_eq!(*lost.lock().unwrap(), 1);
+        assert_eq!(lock.state(), LockState::Lost);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(),
+                   vec![LockEvent::Acquired, LockEvent::Lost]);
+
+        assert!(lock.lock(|| {}).is_ok());
+        assert_eq!(events.try_iter().collect::<Vec<_>>(),
+                   vec![LockEvent::Acquired, LockEvent::Released]);
+    }
+
+    #[test]
+    fn test_losing_lock_to_dropped_heartbeats() {
+        let (clock, server_clock) = (ManualClock::new(), ManualClock::new());
+        let server = new_server().clock(server_clock.clone());
+        let client1 = FaultyClient::new(server.client("client1")).dropped_heartbeats(1.0);
+
+        let lock = Lock::builder("dropped")
+                       .heartbeat_interval(Duration::from_millis(100))
+                       .holder_id("client1")
+                       .clock(clock.clone())
+                       .client(client1)
+                       .build()
+                       .unwrap();
+        let lock2 = new_lock(&server.client("client2"), "dropped");
+
+        // Renewals never reach the sidecar, so the lock expires with its TTL
+        // and client2 takes it over between heartbeats.
+        let guard = lock.try_acquire().unwrap();
+        server_clock.advance(server.ttl());
+        let guard2 = lock2.try_acquire().unwrap();
+        assert!(guard.is_held());
+
+        // The next heartbeat finds it's someone else's, though its renewal
+        // never gets to conflict.
+        assert!(advance_until(&clock, Duration::from_millis(100), 10, || !guard.is_held()));
+        assert!(guard.token().is_cancelled());
+        assert_eq!(lock.state(), LockState::Lost);
+
+        drop(guard);
+        assert!(guard2.is_held());
+        assert_eq!(server.holder("http://localhost:8080/locks/dropped").unwrap().id(), "client2");
+    }
+
+    #[test]
+    fn test_holding_exclusively_under_contention_and_faults() {
+        let server = new_server();
+        let history = History::new();
+
+        // Every replica sees a flaky sidecar, and client0's renewals never
+        // reach it, so that it loses the lock whenever it holds on to it.
+        let handles: Vec<_> = (0..4).map(|i| {
+            let holder = format!("client{}", i);
+            let client = FaultyClient::new(server.client(holder.as_str()))
+                             .connection_errors(0.05)
+                             .server_errors(0.05)
+                             .dropped_heartbeats(if i == 0 { 1.0 } else { 0.0 });
+            let lock = Lock::builder("contended")
+                           .heartbeat_interval(Duration::from_millis(100))
+                           .ttl(server.ttl())
+                           .client(client)
+                           .build()
+                           .unwrap();
+            history.record(&holder, &lock);
+
+            spawn(move || {
+                let mut rng = thread_rng();
+                let policy = RetryPolicy::forever()
+                                 .initial_delay(Duration::from_millis(20))
+                                 .max_delay(Duration::from_millis(100))
+                                 .max_elapsed(Duration::from_secs(2));
+                let started = Instant::now();
+                while started.elapsed() < Duration::from_secs(3) {
+                    let hold = Duration::from_millis(rng.gen_range(0, if i == 0 { 1500 } else { 100 }));
+                    let _ = lock.lock_with_policy(policy.clone(), || sleep(hold));
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let entries = history.entries();
+        assert!(entries.iter().any(|entry| entry.event() == LockEvent::Acquired));
+        if let Err(overlap) = history.check() {
+            panic!("{} in {:?}", overlap, entries);
+        }
+    }
+
+    #[test]
+    fn test_giving_up_lock_after_failed_renewals() {
+        let clock = ManualClock::new();
+        let server = new_server();
+        let client = FaultyClient::new(server.client("client1"));
+        let lock = Lock::builder("cut-off")
+                       .heartbeat_interval(Duration::from_millis(100))
+                       .ttl(Duration::from_secs(1))
+                       .clock(clock.clone())
+                       .client(client.clone())
+                       .build()
+                       .unwrap();
+
+        // Cut off from the sidecar, the lock is held on to while it could
+        // still be renewed, and given up a heartbeat before its TTL is out.
+        let guard = lock.try_acquire().unwrap();
+        client.partition();
+        for _ in 0..8 {
+            clock.advance(Duration::from_millis(100));
+            sleep(Duration::from_millis(50));
+        }
+        assert!(guard.is_held());
+        assert!(!guard.token().is_cancelled());
+
+        assert!(advance_until(&clock, Duration::from_millis(100), 2, || !guard.is_held()));
+        assert!(guard.token().is_cancelled());
+        assert_eq!(lock.state(), LockState::Lost);
+    }
+
+    #[test]
+    fn test_giving_up_lock_after_default_ttl() {
+        let clock = ManualClock::new();
+        let server = new_server();
+        let client = FaultyClient::new(server.client("client1"));
+        let lock = Lock::builder("cut-off")
+                       .heartbeat_interval(Duration::from_millis(100))
+                       .clock(clock.clone())
+                       .client(client.clone())
+                       .build()
+                       .unwrap();
+
+        // Without a TTL of its own, the lock's held on to for as long as the
+        // sidecar holds locks by default.
+        let guard = lock.try_acquire().unwrap();
+        client.partition();
+        clock.advance(DEFAULT_TTL - Duration::from_secs(1));
+        sleep(Duration::from_millis(50));
+        assert!(guard.is_held());
+
+        assert!(advance_until(&clock, Duration::from_millis(100), 10, || !guard.is_held()));
+        assert_eq!(lock.state(), LockState::Lost);
+    }
+
+    #[test]
+    fn test_tracking_state() {
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock  = new_lock(&client1, "state");
+        let lock2 = new_lock(&client2, "state");
+        assert_eq!(lock.state(), LockState::Idle);
+
+        let policy = RetryPolicy::forever()
+                         .max_attempts(3)
+                         .initial_delay(Duration::from_millis(200))
+                         .jitter(Jitter::None);
+
+        let result = lock.lock(|| {
+            assert_eq!(lock.state(), LockState::Holding);
+
+            let waiter = lock2.clone();
+            let waiting = spawn(move || waiter.lock_with_policy(policy, || {}));
+            sleep(Duration::from_millis(100));
+            assert_eq!(lock2.state(), LockState::Waiting);
+
+            waiting.join().unwrap()
+        });
+
+        match result {
+            Ok(Err(SyncError::RetriesExhausted)) => {},
+            other => panic!("expected SyncError::RetriesExhausted, got {:?}", other),
+        }
+        assert_eq!(lock.state(), LockState::Released);
+        assert_eq!(lock2.state(), LockState::Idle);
+    }
+
+    #[test]
+    fn test_cancelling_on_lost_lock() {
+        let server = new_server();
+        let client = server.client("client1");
+        let lock = new_lock(&client, "cancelled");
+
+        let result = lock.lock_cancellable(|token| {
+            server.expire_all();
+
+            let mut batches = 0;
+            while !token.is_cancelled() && batches < 100 {
+                sleep(Duration::from_millis(50));
+                batches += 1;
+            }
+            batches
+        });
+
+        match result {
+            Err(SyncError::LockLost) => {},
+            other => panic!("expected SyncError::LockLost, got {:?}", other),
+        }
+
+        let mut cancelled = false;
+        let _ = lock.lock_cancellable(|token| {
+            lock.unlock();
+            cancelled = token.is_cancelled();
+        });
+        assert!(cancelled);
+
+        let token = lock.lock_cancellable(|token| token).unwrap();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_acquiring_guard() {
+        let server = new_server();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        let lock  = new_lock(&client1, "guarded");
+        let lock2 = new_lock(&client2, "guarded");
+
+        {
+            let guard = lock.try_acquire().unwrap();
+            assert!(guard.is_held());
+            assert!(lock.is_locked());
+
+            match lock2.try_acquire() {
+                Err(SyncError::Held) => {},
+                Err(err) => panic!("expected SyncError::Held, got {:?}", err),
+                Ok(_) => panic!("expected SyncError::Held, got a guard"),
+            }
+        }
+        assert_eq!(lock.is_locked(), false);
+
+        let guard = lock2.acquire().unwrap();
+        assert!(guard.is_held());
+    }
+
+    #[test]
+    fn test_dropping_guard_after_reacquiring() {
+        let server = new_server();
+        let lock = new_lock(&server.client("client1"), "reacquired");
+        let lock2 = new_lock(&server.client("client2"), "reacquired");
+
+        // A guard outliving its acquisition leaves the next one alone, be it
+        // made through the same lock or a clone of it.
+        let clone = lock.clone();
+        for reacquiring in vec![&lock, &clone] {
+            let stale = lock.try_acquire().unwrap();
+            lock.unlock();
+            assert!(!stale.is_held());
+
+            let guard = reacquiring.try_acquire().unwrap();
+            drop(stale);
+            assert!(guard.is_held());
+            assert!(!guard.token().is_cancelled());
+            assert_eq!(server.holder("http://localhost:8080/locks/reacquired").unwrap().id(), "client1");
+
+            // It's still heartbeated, and held against other holders.
+            sleep(Duration::from_millis(250));
+            assert!(guard.is_held());
+            match lock2.try_acquire() {
+                Err(SyncError::Held) => {},
+                Err(err) => panic!("expected SyncError::Held, got {:?}", err),
+                Ok(_) => panic!("expected SyncError::Held, got a guard"),
+            }
+
+            drop(guard);
+            assert_eq!(server.holder("http://localhost:8080/locks/reacquired"), None);
+        }
+    }
+
+    #[test]
+    fn test_rejecting_reentrant_acquisition() {
+        let server = new_server();
+        let lock = new_lock(&server.client("client1"), "reentrant");
+
+        let result = lock.lock(|| lock.try_acquire().map(|_| ()));
+        match result {
+            Ok(Err(SyncError::AlreadyHeld)) => {},
+            other => panic!("expected SyncError::AlreadyHeld, got {:?}", other),
+        }
+        assert_eq!(lock.is_locked(), false);
+        assert_eq!(server.holder("http://localhost:8080/locks/reentrant"), None);
+    }
+
+    #[test]
+    fn test_releasing_without_waiting_for_heartbeat() {
+        let server = new_server();
+        let lock = Lock::builder("slow-heartbeat")
+                       .heartbeat_interval(Duration::from_secs(5))
+                       .client(server.client("client1"))
+                       .build()
+                       .unwrap();
+
+        let start = Instant::now();
+        lock.lock(|| sleep(Duration::from_millis(100))).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let guard = lock.try_acquire().unwrap();
+        let start = Instant::now();
+        drop(guard);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(server.holder("http://localhost:8080/locks/slow-heartbeat"), None);
+    }
+
+    #[test]
+    fn test_locking_through_sidecar() {
+        let sidecar = Sidecar::new()
+                          .addr("127.0.0.1:0".parse().unwrap())
+                          .spawn()
+                          .unwrap();
+        let sidecar_lock = |holder: &str| {
+            Lock::builder("sidecar-lock")
+                .base_uri(sidecar.base_uri())
+                .holder_id(holder)
+                .heartbeat_interval(Duration::from_millis(100))
+                .ttl(Duration::from_secs(1))
+                .build()
+                .unwrap()
+        };
+        let lock = sidecar_lock("client1");
+        let lock2 = sidecar_lock("client2");
+        let events = lock.subscribe();
+
+        {
+            let guard = lock.try_acquire().unwrap();
+            assert!(guard.is_held());
+            let lease = sidecar.store().get("sidecar-lock").unwrap();
+            assert_eq!(lease.holder(), Some("client1"));
+
+            match lock2.try_acquire() {
+                Err(SyncError::Held) => {},
+                Err(err) => panic!("expected SyncError::Held, got {:?}", err),
+                Ok(_) => panic!("expected SyncError::Held, got a guard"),
+            }
+
+            // Held past its TTL, the lock is kept alive by renewals.
+            sleep(Duration::from_millis(1500));
+            assert!(guard.is_held());
+            assert_eq!(sidecar.store().get("sidecar-lock").unwrap().holder(), Some("client1"));
+        }
+        assert!(sidecar.store().get("sidecar-lock").is_none());
+
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(events.first(), Some(&LockEvent::Acquired));
+        assert!(events.contains(&LockEvent::Renewed));
+        assert_eq!(events.last(), Some(&LockEvent::Released));
+
+        assert!(lock2.lock(|| {}).is_ok());
     }
 }
@@ -0,0 +1,268 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+use clock::Clock;
+
+
+/// How a `RetryPolicy` randomizes the delay between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    /// Wait exactly the exponential delay.
+    None,
+    /// Wait a random delay between zero and the exponential delay.
+    Full,
+    /// Wait a random delay between the initial delay and three times the
+    /// previous delay, capped at the maximum delay.
+    Decorrelated,
+}
+
+
+/// How a `Lock` retries acquisition while the lock is held elsewhere.
+///
+/// Delays grow exponentially from `initial_delay` by `multiplier` up to
+/// `max_delay`, and are jittered so that replicas don't retry in lockstep.
+/// Retrying stops after `max_attempts` attempts or once `max_elapsed` has
+/// passed, whichever comes first.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let policy = sync::RetryPolicy::forever()
+///                      .max_attempts(20)
+///                      .initial_delay(Duration::from_millis(500))
+///                      .max_delay(Duration::from_secs(15))
+///                      .jitter(sync::Jitter::Decorrelated);
+///
+///     let lock = sync::Lock::new("some-held-lock", sync::DEFAULT_BASE_URI, 10);
+///     let _ = lock.lock_with_policy(policy, || {
+///         // do some important work
+///     });
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: Option<u32>,
+    max_elapsed: Option<Duration>,
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: Jitter,
+}
+
+impl RetryPolicy {
+    /// Makes a single attempt.
+    pub fn no_retry() -> Self {
+        RetryPolicy::forever().max_attempts(1)
+    }
+
+    /// Retries up to `retries` times after the first attempt.
+    pub fn retries(retries: u32) -> Self {
+        RetryPolicy::forever().max_attempts(retries.saturating_add(1))
+    }
+
+    /// Retries until the lock is acquired.
+    pub fn forever() -> Self {
+        RetryPolicy{
+            max_attempts: None,
+            max_elapsed: None,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: Jitter::Full,
+        }
+    }
+
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    pub fn max_elapsed(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed = Some(elapsed);
+        self
+    }
+
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub(crate) fn backoff(&self, clock: Arc<Clock>) -> Backoff {
+        Backoff{
+            policy: self.clone(),
+            attempts: 0,
+            started: clock.now(),
+            clock: clock,
+            previous: self.initial_delay,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::no_retry()
+    }
+}
+
+
+/// Tracks the attempts made under a `RetryPolicy`.
+pub(crate) struct Backoff {
+    policy: RetryPolicy,
+    attempts: u32,
+    started: Instant,
+    clock: Arc<Clock>,
+    previous: Duration,
+}
+
+impl Backoff {
+    /// The number of failed attempts recorded so far.
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Records a failed attempt and returns how long to wait before the next
+    /// one, or `None` once the policy is exhausted.
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts = self.attempts.saturating_add(1);
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.attempts >= max_attempts {
+                return None
+            }
+        }
+
+        let delay = match self.policy.jitter {
+            Jitter::None => self.exponential(),
+            Jitter::Full => random_between(Duration::from_millis(0), self.exponential()),
+            Jitter::Decorrelated => {
+                let upper = cmp::max(self.policy.initial_delay, self.previous * 3);
+                cmp::min(self.policy.max_delay,
+                         random_between(self.policy.initial_delay, upper))
+            },
+        };
+        self.previous = delay;
+
+        if let Some(max_elapsed) = self.policy.max_elapsed {
+            if self.clock.now() - self.started + delay > max_elapsed {
+                return None
+            }
+        }
+        Some(delay)
+    }
+
+    fn exponential(&self) -> Duration {
+        let exponent = cmp::min(self.attempts - 1, 64) as i32;
+        let millis = as_millis(self.policy.initial_delay) as f64 * self.policy.multiplier.powi(exponent);
+        let max = as_millis(self.policy.max_delay);
+
+        if millis.is_finite() && millis < max as f64 {
+            Duration::from_millis(millis as u64)
+        } else {
+            self.policy.max_delay
+        }
+    }
+}
+
+pub(crate) fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+fn random_between(low: Duration, high: Duration) -> Duration {
+    let (low, high) = (as_millis(low), as_millis(high));
+    if high <= low {
+        return Duration::from_millis(low)
+    }
+    Duration::from_millis(thread_rng().gen_range(low, high + 1))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use clock::SystemClock;
+    use retry::{Jitter, RetryPolicy};
+    use testing::ManualClock;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = RetryPolicy::forever()
+                         .initial_delay(Duration::from_millis(100))
+                         .max_delay(Duration::from_millis(1000))
+                         .jitter(Jitter::None);
+
+        let mut backoff = policy.backoff(Arc::new(SystemClock));
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().unwrap()).collect();
+
+        assert_eq!(delays, vec![Duration::from_millis(100), Duration::from_millis(200),
+                                Duration::from_millis(400), Duration::from_millis(800),
+                                Duration::from_millis(1000), Duration::from_millis(1000)]);
+    }
+
+    #[test]
+    fn test_jittered_backoff_stays_in_bounds() {
+        for jitter in vec![Jitter::Full, Jitter::Decorrelated] {
+            let policy = RetryPolicy::forever()
+                             .initial_delay(Duration::from_millis(100))
+                             .max_delay(Duration::from_millis(1000))
+                             .jitter(jitter);
+
+            let mut backoff = policy.backoff(Arc::new(SystemClock));
+            for _ in 0..50 {
+                assert!(backoff.next_delay().unwrap() <= Duration::from_millis(1000));
+            }
+        }
+    }
+
+    #[test]
+    fn test_exhausting_attempts() {
+        let mut backoff = RetryPolicy::retries(2).backoff(Arc::new(SystemClock));
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_none());
+
+        let mut backoff = RetryPolicy::no_retry().backoff(Arc::new(SystemClock));
+        assert!(backoff.next_delay().is_none());
+
+        let clock = ManualClock::new();
+        let mut backoff = RetryPolicy::forever()
+                              .initial_delay(Duration::from_millis(400))
+                              .max_elapsed(Duration::from_secs(1))
+                              .jitter(Jitter::None)
+                              .backoff(Arc::new(clock.clone()));
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(400)));
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(backoff.next_delay(), None);
+    }
+}
@@ -0,0 +1,822 @@
+// Copyright 2018 Christopher MacGown
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "async")]
+use futures::future;
+use rand::{thread_rng, Rng};
+use requests::StatusCode;
+
+#[cfg(feature = "async")]
+use async_lock::{AsyncLockClient, LockFuture};
+use clock::{self, Clock, SystemClock};
+use client::{AcquireStatus, LockClient, LockHolder, LockStatus, ReleaseStatus};
+use error::SyncError;
+use lock::{Lock, LockEvent, DEFAULT_TTL};
+
+
+// How often a `ManualClock` sleeper wakes up to let its caller check
+// whether it's been stopped, in real time.
+const MANUAL_SLEEP_POLL: Duration = Duration::from_millis(10);
+
+
+/// A clock that only moves when it's advanced, for deterministic tests of
+/// heartbeats, retries and expiry.
+///
+/// Clones share the same time.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::time::Duration;
+/// use sync::LockClient;
+///
+/// fn main() {
+///     let clock = sync::ManualClock::new();
+///     let server = sync::InMemoryLockServer::with_ttl(Duration::from_secs(15))
+///                      .clock(clock.clone());
+///
+///     server.client("replica-1").put_lock("some-lock").unwrap();
+///     clock.advance(Duration::from_secs(10));
+///     assert!(server.holder("some-lock").is_some());
+///
+///     clock.advance(Duration::from_secs(5));
+///     assert!(server.holder("some-lock").is_none());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<ManualTime>);
+
+#[derive(Debug)]
+struct ManualTime {
+    started: Instant,
+    started_at: SystemTime,
+    elapsed: Mutex<Duration>,
+    advanced: Condvar,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock(Arc::new(ManualTime{
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+            advanced: Condvar::new(),
+        }))
+    }
+
+    /// Moves the clock forward by `duration`, waking sleepers whose time
+    /// has come.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.elapsed.lock().unwrap() += duration;
+        self.0.advanced.notify_all();
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.0.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.started + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.0.started_at + self.elapsed()
+    }
+
+    // Returns early every so often, as a sleeping heartbeat would otherwise
+    // never notice being stopped until the clock is advanced.
+    fn sleep(&self, duration: Duration) {
+        let mut elapsed = self.0.elapsed.lock().unwrap();
+        let until = *elapsed + duration;
+        while *elapsed < until {
+            let (guard, timeout) = self.0.advanced.wait_timeout(elapsed, MANUAL_SLEEP_POLL).unwrap();
+            if timeout.timed_out() {
+                return
+            }
+            elapsed = guard;
+        }
+    }
+
+    // Waits for a notification for no longer than `sleep` would, since
+    // advancing the clock doesn't notify `condition`.
+    fn wait_timeout<'a>(&self, condition: &Condvar, guard: MutexGuard<'a, ()>, _: Duration)
+                        -> MutexGuard<'a, ()> {
+        condition.wait_timeout(guard, MANUAL_SLEEP_POLL).unwrap().0
+    }
+}
+
+
+/// A lock sidecar that lives in the process, for tests and single-process
+/// use.
+///
+/// Locks expire `ttl` after they were last acquired or renewed, and conflict
+/// while they're held by anyone else, as they do on the sidecar. Clones
+/// share the same locks, and every `client` reaches them as a different
+/// holder.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let server = sync::InMemoryLockServer::with_ttl(Duration::from_secs(1));
+///
+///     let lock = sync::Lock::builder("some-lock")
+///                    .heartbeat_interval(Duration::from_millis(100))
+///                    .client(server.client("replica-1"))
+///                    .build()
+///                    .expect("a valid lock configuration");
+///
+///     lock.lock(|| {
+///         assert_eq!(server.holder("http://localhost:8080/locks/some-lock")
+///                          .map(|holder| holder.id().to_string()),
+///                    Some("replica-1".to_string()));
+///     }).expect("nobody else holds the lock");
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct InMemoryLockServer {
+    ttl: Duration,
+    clock: Arc<Clock>,
+    locks: Arc<Mutex<HashMap<String, Lease>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Lease {
+    holder: String,
+    acquired_at: SystemTime,
+    renewed_at: Instant,
+}
+
+impl InMemoryLockServer {
+    /// Creates a server holding locks for 30 seconds.
+    pub fn new() -> Self {
+        InMemoryLockServer::with_ttl(DEFAULT_TTL)
+    }
+
+    pub fn with_ttl(ttl: Duration) -> Self {
+        InMemoryLockServer{
+            ttl: ttl,
+            clock: Arc::new(SystemClock),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Expires locks by `clock` rather than the system's.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// A client that acquires locks as `holder_id`.
+    pub fn client<S: Into<String>>(&self, holder_id: S) -> InMemoryLockClient {
+        InMemoryLockClient{
+            holder_id: holder_id.into(),
+            server: self.clone(),
+        }
+    }
+
+    /// Who holds `lock`, if it hasn't expired.
+    pub fn holder(&self, lock: &str) -> Option<LockHolder> {
+        let mut locks = self.locks.lock().unwrap();
+        self.lease(&mut locks, lock).map(|lease| self.describe(lease))
+    }
+
+    /// Expires `lock`, as if its holder stopped renewing it.
+    pub fn expire(&self, lock: &str) {
+        self.locks.lock().unwrap().remove(lock);
+    }
+
+    /// Expires every lock.
+    pub fn expire_all(&self) {
+        self.locks.lock().unwrap().clear();
+    }
+
+    // Forgets `lock` once it's expired, and returns its lease otherwise.
+    fn lease<'a>(&self, locks: &'a mut HashMap<String, Lease>, lock: &str) -> Option<&'a Lease> {
+        let expired = match locks.get(lock) {
+            Some(lease) => self.clock.now() - lease.renewed_at >= self.ttl,
+            None => return None,
+        };
+        if expired {
+            locks.remove(lock);
+        }
+        locks.get(lock)
+    }
+
+    fn describe(&self, lease: &Lease) -> LockHolder {
+        let elapsed = self.clock.now() - lease.renewed_at;
+        let remaining = self.ttl.checked_sub(elapsed).unwrap_or(Duration::from_secs(0));
+        let expires_at = self.clock.system_time() + remaining;
+        LockHolder::new(lease.holder.as_str())
+            .acquired_at(lease.acquired_at)
+            .expires_at(expires_at)
+    }
+}
+
+impl Default for InMemoryLockServer {
+    fn default() -> Self {
+        InMemoryLockServer::new()
+    }
+}
+
+
+/// A holder's connection to an `InMemoryLockServer`.
+#[derive(Debug, Clone)]
+pub struct InMemoryLockClient {
+    holder_id: String,
+    server: InMemoryLockServer,
+}
+
+impl InMemoryLockClient {
+    pub fn holder_id(&self) -> &str {
+        &self.holder_id
+    }
+}
+
+impl LockClient for InMemoryLockClient {
+    fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError> {
+        match self.server.holder(lock) {
+            Some(holder) => Ok(LockStatus::Held(Some(holder))),
+            None => Ok(LockStatus::Free),
+        }
+    }
+
+    fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError> {
+        let mut locks = self.server.locks.lock().unwrap();
+
+        let acquired_at = match self.server.lease(&mut locks, lock) {
+            Some(lease) if lease.holder != self.holder_id => return Ok(AcquireStatus::Conflict),
+            Some(lease) => lease.acquired_at,
+            None => self.server.clock.system_time(),
+        };
+        locks.insert(lock.to_string(), Lease{
+            holder: self.holder_id.clone(),
+            acquired_at: acquired_at,
+            renewed_at: self.server.clock.now(),
+        });
+        Ok(AcquireStatus::Acquired)
+    }
+
+    fn delete_lock(&self, lock: &str) -> Result<ReleaseStatus, SyncError> {
+        let mut locks = self.server.locks.lock().unwrap();
+
+        if let Some(false) = self.server.lease(&mut locks, lock).map(|lease| lease.holder == self.holder_id) {
+            return Ok(ReleaseStatus::NotHeld)
+        }
+        locks.remove(lock);
+        Ok(ReleaseStatus::Released)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncLockClient for InMemoryLockClient {
+    fn get_lock(&self, lock: &str) -> LockFuture<LockStatus> {
+        Box::new(future::result(LockClient::get_lock(self, lock)))
+    }
+
+    fn put_lock(&self, lock: &str) -> LockFuture<AcquireStatus> {
+        Box::new(future::result(LockClient::put_lock(self, lock)))
+    }
+
+    fn delete_lock(&self, lock: &str) -> LockFuture<ReleaseStatus> {
+        Box::new(future::result(LockClient::delete_lock(self, lock)))
+    }
+}
+
+
+/// A fault `FaultyClient` injects into a single request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Passes the request through.
+    Pass,
+    /// Passes the request through after a delay.
+    Delay(Duration),
+    /// Fails the request without it reaching the sidecar.
+    ConnectionError,
+    /// Passes the request through, but fails it as if the response was lost.
+    ResponseLost,
+    /// Answers the request with a sidecar error instead of passing it
+    /// through.
+    ServerError(StatusCode),
+}
+
+/// Wraps a `LockClient` to make the sidecar look flaky, for chaos tests.
+///
+/// Requests are delayed by the configured latency, and then fail at random
+/// with connection errors and 5xx responses at the configured rates.
+/// Renewals of a lock this client holds can be dropped on their way to the
+/// sidecar, and the whole client can be cut off with `partition`. Faults
+/// passed to `script` override all of these, one request at a time, so that
+/// exact failure sequences can be replayed.
+///
+/// Clones share the same faults, so a test can keep one to change them
+/// while a `Lock` or `Election` uses another.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use sync::{Fault, SyncError};
+///
+/// fn main() {
+///     let server = sync::InMemoryLockServer::new();
+///     let client = sync::FaultyClient::new(server.client("replica-1"))
+///                      .connection_errors(0.1);
+///
+///     let lock = sync::Lock::builder("some-lock")
+///                    .client(client.clone())
+///                    .build()
+///                    .expect("a valid lock configuration");
+///
+///     client.script(vec![Fault::ConnectionError]);
+///     match lock.lock(|| {}) {
+///         Err(SyncError::Transport(_)) => {},
+///         other => panic!("expected a transport error, got {:?}", other),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct FaultyClient<C> {
+    client: Arc<C>,
+    clock: Arc<Clock>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    latency: Duration,
+    connection_errors: f64,
+    server_errors: f64,
+    dropped_heartbeats: f64,
+    partitioned: bool,
+    script: VecDeque<Fault>,
+    // The locks this client has acquired, whose PUTs are renewals.
+    holding: HashSet<String>,
+}
+
+impl<C: LockClient> FaultyClient<C> {
+    pub fn new(client: C) -> Self {
+        FaultyClient{
+            client: Arc::new(client),
+            clock: Arc::new(SystemClock),
+            faults: Arc::new(Mutex::new(Faults::default())),
+        }
+    }
+
+    /// Delays every request by `latency`.
+    pub fn latency(self, latency: Duration) -> Self {
+        self.faults.lock().unwrap().latency = latency;
+        self
+    }
+
+    /// Fails a `rate` fraction of requests without them reaching the sidecar.
+    pub fn connection_errors(self, rate: f64) -> Self {
+        self.faults.lock().unwrap().connection_errors = rate;
+        self
+    }
+
+    /// Answers a `rate` fraction of requests with 503s.
+    pub fn server_errors(self, rate: f64) -> Self {
+        self.faults.lock().unwrap().server_errors = rate;
+        self
+    }
+
+    /// Drops a `rate` fraction of renewals of the locks this client holds,
+    /// which fail as connection errors.
+    pub fn dropped_heartbeats(self, rate: f64) -> Self {
+        self.faults.lock().unwrap().dropped_heartbeats = rate;
+        self
+    }
+
+    /// Delays requests by `clock` rather than the system's.
+    pub fn clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Cuts this client off from the sidecar until `heal` is called.
+    pub fn partition(&self) {
+        self.faults.lock().unwrap().partitioned = true;
+    }
+
+    pub fn heal(&self) {
+        self.faults.lock().unwrap().partitioned = false;
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.faults.lock().unwrap().partitioned
+    }
+
+    /// Injects `faults` into the next requests, in order, before going back
+    /// to the configured ones.
+    pub fn script<I: IntoIterator<Item = Fault>>(&self, faults: I) {
+        self.faults.lock().unwrap().script.extend(faults);
+    }
+
+    fn next_fault(&self, lock: &str, renewal: bool) -> Fault {
+        let mut faults = self.faults.lock().unwrap();
+        if let Some(fault) = faults.script.pop_front() {
+            return fault
+        }
+        if faults.partitioned {
+            return Fault::ConnectionError
+        }
+
+        let mut rng = thread_rng();
+        let renewing = renewal && faults.holding.contains(lock);
+        if renewing && rng.gen::<f64>() < faults.dropped_heartbeats {
+            return Fault::ConnectionError
+        }
+        if rng.gen::<f64>() < faults.connection_errors {
+            return Fault::ConnectionError
+        }
+        if rng.gen::<f64>() < faults.server_errors {
+            return Fault::ServerError(StatusCode::ServiceUnavailable)
+        }
+        match faults.latency {
+            latency if latency > Duration::from_secs(0) => Fault::Delay(latency),
+            _ => Fault::Pass,
+        }
+    }
+
+    fn inject<T, F>(&self, lock: &str, renewal: bool, request: F) -> Result<T, SyncError>
+    where F: FnOnce(&C) -> Result<T, SyncError>
+    {
+        match self.next_fault(lock, renewal) {
+            Fault::Pass => request(&self.client),
+            Fault::Delay(delay) => {
+                let deadline = self.clock.now() + delay;
+                clock::sleep_until(&*self.clock, deadline, || false);
+                request(&self.client)
+            },
+            Fault::ConnectionError => Err(connection_error(lock)),
+            Fault::ResponseLost => {
+                let _ = request(&self.client);
+                Err(connection_error(lock))
+            },
+            Fault::ServerError(status) => Err(SyncError::UnexpectedStatus(status)),
+        }
+    }
+}
+
+fn connection_error(lock: &str) -> SyncError {
+    let err = io::Error::new(io::ErrorKind::ConnectionRefused, format!("injected fault for {}", lock));
+    SyncError::Transport(err.into())
+}
+
+impl<C> Clone for FaultyClient<C> {
+    fn clone(&self) -> Self {
+        FaultyClient{
+            client: self.client.clone(),
+            clock: self.clock.clone(),
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+impl<C: LockClient> LockClient for FaultyClient<C> {
+    fn get_lock(&self, lock: &str) -> Result<LockStatus, SyncError> {
+        let status = self.inject(lock, false, |client| client.get_lock(lock))?;
+
+        // Once a lock has expired, asking for it again acquires it afresh.
+        if status == LockStatus::Free {
+            self.faults.lock().unwrap().holding.remove(lock);
+        }
+        Ok(status)
+    }
+
+    fn put_lock(&self, lock: &str) -> Result<AcquireStatus, SyncError> {
+        let status = self.inject(lock, true, |client| client.put_lock(lock))?;
+
+        let mut faults = self.faults.lock().unwrap();
+        match status {
+            AcquireStatus::Acquired => faults.holding.insert(lock.to_string()),
+            AcquireStatus::Conflict => faults.holding.remove(lock),
+        };
+        Ok(status)
+    }
+
+    fn delete_lock(&self, lock: &str) -> Result<ReleaseStatus, SyncError> {
+        self.faults.lock().unwrap().holding.remove(lock);
+        self.inject(lock, false, |client| client.delete_lock(lock))
+    }
+}
+
+// Faults are injected as the request is made, so a delay blocks the thread
+// that polls the future for it.
+#[cfg(feature = "async")]
+impl<C: LockClient> AsyncLockClient for FaultyClient<C> {
+    fn get_lock(&self, lock: &str) -> LockFuture<LockStatus> {
+        Box::new(future::result(LockClient::get_lock(self, lock)))
+    }
+
+    fn put_lock(&self, lock: &str) -> LockFuture<AcquireStatus> {
+        Box::new(future::result(LockClient::put_lock(self, lock)))
+    }
+
+    fn delete_lock(&self, lock: &str) -> LockFuture<ReleaseStatus> {
+        Box::new(future::result(LockClient::delete_lock(self, lock)))
+    }
+}
+
+
+/// Records who acquired, released and lost locks, and when, to check that
+/// no two holders ever held the same lock at once.
+///
+/// Events are timestamped as they're recorded, in the order they happen,
+/// so a holder is considered to own a lock from when its `Lock` reports it
+/// acquired until it reports it released or lost.
+///
+/// # Example
+/// ```
+/// extern crate metaparticle_sync as sync;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let server = sync::InMemoryLockServer::new();
+///     let history = sync::History::new();
+///
+///     let lock = sync::Lock::builder("some-lock")
+///                    .heartbeat_interval(Duration::from_millis(100))
+///                    .client(server.client("replica-1"))
+///                    .build()
+///                    .expect("a valid lock configuration");
+///     history.record("replica-1", &lock);
+///
+///     let _ = lock.lock(|| {});
+///     assert_eq!(history.entries().len(), 2);
+///     assert!(history.check().is_ok());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct History {
+    clock: Arc<Clock>,
+    entries: Arc<Mutex<Vec<HistoryEntry>>>,
+}
+
+/// A change in a lock's ownership, as recorded by a `History`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    lock: String,
+    holder: String,
+    event: LockEvent,
+    at: Instant,
+}
+
+impl HistoryEntry {
+    pub fn lock(&self) -> &str {
+        &self.lock
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    pub fn event(&self) -> LockEvent {
+        self.event
+    }
+
+    pub fn at(&self) -> Instant {
+        self.at
+    }
+}
+
+/// Two holders that owned a lock at the same time, as found by
+/// `History::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    /// The entry that acquired the lock while it was held.
+    pub acquired: HistoryEntry,
+    /// The holder that still owned it.
+    pub held_by: String,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} acquired {} while {} held it",
+               self.acquired.holder, self.acquired.lock, self.held_by)
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        History{
+            clock: Arc::new(SystemClock),
+            entries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Timestamps entries by `clock` rather than the system's.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Records every acquisition, release and loss of `lock` as `holder`'s.
+    pub fn record(&self, holder: &str, lock: &Lock) {
+        let history = self.clone();
+        let (name, holder) = (lock.name().to_string(), holder.to_string());
+        lock.on_event(move |event| {
+            if event != LockEvent::Renewed {
+                history.record_event(&name, &holder, event);
+            }
+        });
+    }
+
+    /// Records `event` for `holder`, for ownership that isn't tracked by a
+    /// `Lock`, such as an election's leadership.
+    pub fn record_event(&self, lock: &str, holder: &str, event: LockEvent) {
+        let mut entries = self.entries.lock().unwrap();
+        let at = self.clock.now();
+        entries.push(HistoryEntry{
+            lock: lock.to_string(),
+            holder: holder.to_string(),
+            event: event,
+            at: at,
+        });
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Checks that every lock had at most one holder at a time, returning
+    /// the first overlap found otherwise.
+    pub fn check(&self) -> Result<(), Overlap> {
+        let mut owners: HashMap<&str, &str> = HashMap::new();
+        let entries = self.entries.lock().unwrap();
+
+        for entry in entries.iter() {
+            match entry.event {
+                LockEvent::Acquired => {
+                    if let Some(owner) = owners.insert(&entry.lock, &entry.holder) {
+                        if owner != entry.holder {
+                            return Err(Overlap{
+                                acquired: entry.clone(),
+                                held_by: owner.to_string(),
+                            })
+                        }
+                    }
+                },
+                LockEvent::Released | LockEvent::Lost => {
+                    if owners.get(entry.lock.as_str()) == Some(&entry.holder.as_str()) {
+                        owners.remove(entry.lock.as_str());
+                    }
+                },
+                LockEvent::Renewed => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use requests::StatusCode;
+
+    use client::{AcquireStatus, LockClient, LockStatus, ReleaseStatus};
+    use error::SyncError;
+    use lock::LockEvent;
+    use testing::{Fault, FaultyClient, History, InMemoryLockServer};
+
+    #[test]
+    fn test_holding_locks_until_they_expire() {
+        let server = InMemoryLockServer::with_ttl(Duration::from_millis(200));
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        assert_eq!(client1.get_lock("lock").unwrap(), LockStatus::Free);
+        assert_eq!(client1.put_lock("lock").unwrap(), AcquireStatus::Acquired);
+        assert_eq!(client2.put_lock("lock").unwrap(), AcquireStatus::Conflict);
+        assert_eq!(client2.delete_lock("lock").unwrap(), ReleaseStatus::NotHeld);
+
+        let acquired = server.holder("lock").unwrap();
+        assert_eq!(acquired.id(), "client1");
+
+        sleep(Duration::from_millis(100));
+        assert_eq!(client1.put_lock("lock").unwrap(), AcquireStatus::Acquired);
+        assert_eq!(server.holder("lock").unwrap().acquired(), acquired.acquired());
+        sleep(Duration::from_millis(150));
+        assert_eq!(client2.put_lock("lock").unwrap(), AcquireStatus::Conflict);
+
+        sleep(Duration::from_millis(100));
+        assert_eq!(client2.get_lock("lock").unwrap(), LockStatus::Free);
+        assert_eq!(client2.put_lock("lock").unwrap(), AcquireStatus::Acquired);
+    }
+
+    #[test]
+    fn test_releasing_locks() {
+        let server = InMemoryLockServer::new();
+        let client1 = server.client("client1");
+        let client2 = server.client("client2");
+
+        assert_eq!(client1.put_lock("lock").unwrap(), AcquireStatus::Acquired);
+        assert_eq!(client1.delete_lock("lock").unwrap(), ReleaseStatus::Released);
+        assert_eq!(client2.put_lock("lock").unwrap(), AcquireStatus::Acquired);
+
+        server.expire("lock");
+        assert_eq!(client1.get_lock("lock").unwrap(), LockStatus::Free);
+    }
+
+    #[test]
+    fn test_injecting_scripted_faults() {
+        let server = InMemoryLockServer::new();
+        let client = FaultyClient::new(server.client("client1"));
+
+        client.script(vec![Fault::ConnectionError,
+                           Fault::ServerError(StatusCode::BadGateway),
+                           Fault::ResponseLost,
+                           Fault::Delay(Duration::from_millis(10))]);
+
+        match client.put_lock("lock") {
+            Err(SyncError::Transport(_)) => {},
+            other => panic!("expected a transport error, got {:?}", other),
+        }
+        assert_eq!(server.holder("lock"), None);
+
+        match client.put_lock("lock") {
+            Err(SyncError::UnexpectedStatus(StatusCode::BadGateway)) => {},
+            other => panic!("expected an unexpected status error, got {:?}", other),
+        }
+
+        // The sidecar acts on requests whose responses are lost.
+        match client.put_lock("lock") {
+            Err(SyncError::Transport(_)) => {},
+            other => panic!("expected a transport error, got {:?}", other),
+        }
+        assert!(server.holder("lock").is_some());
+
+        assert_eq!(client.delete_lock("lock").unwrap(), ReleaseStatus::Released);
+        assert_eq!(client.get_lock("lock").unwrap(), LockStatus::Free);
+    }
+
+    #[test]
+    fn test_partitioning_clients() {
+        let server = InMemoryLockServer::new();
+        let client1 = FaultyClient::new(server.client("client1"));
+        let client2 = server.client("client2");
+
+        client1.partition();
+        assert!(client1.get_lock("lock").is_err());
+        assert_eq!(client2.put_lock("lock").unwrap(), AcquireStatus::Acquired);
+
+        client1.heal();
+        assert_eq!(client1.put_lock("lock").unwrap(), AcquireStatus::Conflict);
+    }
+
+    #[test]
+    fn test_checking_histories() {
+        let history = History::new();
+        history.record_event("lock", "client1", LockEvent::Acquired);
+        history.record_event("other-lock", "client2", LockEvent::Acquired);
+        history.record_event("lock", "client1", LockEvent::Released);
+        history.record_event("lock", "client2", LockEvent::Acquired);
+        history.record_event("lock", "client2", LockEvent::Lost);
+        assert!(history.check().is_ok());
+
+        history.record_event("lock", "client1", LockEvent::Acquired);
+        history.record_event("lock", "client3", LockEvent::Acquired);
+        let overlap = history.check().unwrap_err();
+        assert_eq!(overlap.acquired.holder(), "client3");
+        assert_eq!(overlap.held_by, "client1");
+    }
+}